@@ -0,0 +1,196 @@
+use crate::extract::{Json, Path};
+use crate::storage::Change;
+use crate::{error::Error, nft::Firewall, state::SharedState, wghelper::Wg};
+use axum::{http::StatusCode, Extension};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+/// One destination a peer with an ACL may reach. A peer without any rules
+/// may reach everything.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AclRule {
+    #[serde(default)]
+    pub id: Uuid,
+    pub destination: IpNet,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub ports: Vec<PortRange>,
+}
+
+impl AclRule {
+    /// Ports only mean something for TCP and UDP.
+    pub fn is_valid(&self) -> bool {
+        self.ports.is_empty() || matches!(self.protocol, Protocol::Tcp | Protocol::Udp)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Any,
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+/// A single port or an inclusive range, written as `443` or `8000-8100`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(ports: String) -> Result<PortRange, String> {
+        let invalid = || format!("invalid port range {}", ports);
+        let (start, end) = ports.split_once('-').unwrap_or((&ports, &ports));
+        let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+        let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+        if start == 0 || start > end {
+            return Err(invalid());
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(ports: PortRange) -> String {
+        ports.to_string()
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+pub async fn get_acl(
+    Path((iface, peer)): Path<(String, String)>,
+    Extension(state): Extension<SharedState>,
+) -> Result<Json<Vec<AclRule>>, Error> {
+    let state = state.read().await;
+    let server_id = state.server_id(&iface)?;
+    let peer_id = state.peer_id(server_id, &peer)?;
+    Ok(Json(state.servers[server_id].peers[peer_id].acl.clone()))
+}
+
+pub async fn get_rule(
+    Path((iface, peer, rule)): Path<(String, String, Uuid)>,
+    Extension(state): Extension<SharedState>,
+) -> Result<Json<AclRule>, Error> {
+    let state = state.read().await;
+    let server_id = state.server_id(&iface)?;
+    let peer_id = state.peer_id(server_id, &peer)?;
+    let acl = &state.servers[server_id].peers[peer_id].acl;
+    acl.iter()
+        .find(|other| other.id == rule)
+        .map(|rule| Json(rule.clone()))
+        .ok_or_else(|| rule_not_found(rule))
+}
+
+pub async fn create_rule(
+    Path((iface, peer)): Path<(String, String)>,
+    Extension(state): Extension<SharedState>,
+    Json(mut rule): Json<AclRule>,
+) -> Result<Json<AclRule>, Error> {
+    if !rule.is_valid() {
+        return Err(invalid_rule());
+    }
+    rule.id = Uuid::new_v4();
+    change_acl(&state, &iface, &peer, |acl| {
+        acl.push(rule.clone());
+        Ok(())
+    })
+    .await?;
+    Ok(Json(rule))
+}
+
+pub async fn update_rule(
+    Path((iface, peer, id)): Path<(String, String, Uuid)>,
+    Extension(state): Extension<SharedState>,
+    Json(mut rule): Json<AclRule>,
+) -> Result<StatusCode, Error> {
+    if !rule.is_valid() {
+        return Err(invalid_rule());
+    }
+    rule.id = id;
+    change_acl(&state, &iface, &peer, |acl| {
+        let other = acl
+            .iter_mut()
+            .find(|other| other.id == id)
+            .ok_or_else(|| rule_not_found(id))?;
+        *other = rule;
+        Ok(())
+    })
+    .await
+}
+
+pub async fn delete_rule(
+    Path((iface, peer, id)): Path<(String, String, Uuid)>,
+    Extension(state): Extension<SharedState>,
+) -> Result<StatusCode, Error> {
+    change_acl(&state, &iface, &peer, |acl| {
+        let len = acl.len();
+        acl.retain(|other| other.id != id);
+        if acl.len() == len {
+            return Err(rule_not_found(id));
+        }
+        Ok(())
+    })
+    .await
+}
+
+fn invalid_rule() -> Error {
+    Error::bad_request("Ports only apply to TCP and UDP")
+}
+
+fn rule_not_found(id: Uuid) -> Error {
+    Error::NotFound(format!("No rule {}", id))
+}
+
+/// Applies a change to a peer's ACL, saves it and reloads the interface's
+/// firewall rules if it is running. The change fails if the rule it was
+/// looking for does not exist.
+async fn change_acl<F>(
+    state: &SharedState,
+    iface: &str,
+    peer: &str,
+    change: F,
+) -> Result<StatusCode, Error>
+where
+    F: FnOnce(&mut Vec<AclRule>) -> Result<(), Error>,
+{
+    let mut state = state.write().await;
+    let server_id = state.server_id(iface)?;
+    let peer_id = state.peer_id(server_id, peer)?;
+
+    // Only the nftables backend knows how to enforce ACLs.
+    if state.servers[server_id].firewall != Firewall::Nftables {
+        return Err(Error::bad_request("ACLs need the nftables firewall"));
+    }
+
+    let peer = &mut state.servers[server_id].peers[peer_id];
+    change(&mut peer.acl)?;
+    let change = Change::Peer(peer.id);
+
+    Wg::dump_state(&state, &[change]).await?;
+    if Wg::server_status()
+        .await?
+        .contains(&state.servers[server_id].name)
+    {
+        state.hot_reload(server_id).await?;
+    }
+    Ok(StatusCode::OK)
+}
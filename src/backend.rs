@@ -0,0 +1,352 @@
+use crate::masterkey;
+use crate::metrics;
+use crate::nat::{self, Hooks};
+use crate::netlink;
+use crate::nft::{self, Firewall};
+use crate::simulator::Simulator;
+use crate::stats::{self, PeerStats};
+use crate::tc;
+use crate::userspace;
+use crate::wghelper::{join, Server};
+use async_trait::async_trait;
+use chrono::Utc;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    process::Command,
+};
+
+/// Everything rest-wg does to the host to put its state into effect.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    /// Creates the interface for a server and brings it up.
+    async fn up(&self, server: &Server) -> Result<(), String>;
+
+    /// Takes the interface of a server down and removes it.
+    async fn down(&self, server: &Server) -> Result<(), String>;
+
+    /// Brings a running interface in line with its server's active peers,
+    /// leaving the sessions of unchanged peers alone.
+    async fn sync(&self, server: &Server) -> Result<(), String>;
+
+    /// Removes the route for a network that no longer sits behind a peer.
+    async fn remove_route(&self, name: &str, cidr: &IpNet);
+
+    /// The names of the interfaces that are running.
+    async fn interfaces(&self) -> Result<HashSet<String>, String>;
+
+    /// Reads the peers of a running interface, keyed by public key. An
+    /// interface that is not running has no peers.
+    async fn stats(&self, name: &str) -> HashMap<String, PeerStats>;
+
+    /// Generates a private key and derives its public key, both base64 encoded.
+    fn generate_keys(&self) -> (String, String);
+}
+
+/// Which WireGuard implementation runs an interface.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// The kernel module, or whatever wg-quick falls back to without it.
+    #[default]
+    Kernel,
+    /// An implementation embedded in rest-wg, for hosts that cannot load the
+    /// kernel module.
+    Userspace,
+}
+
+static BACKEND: OnceLock<Box<dyn Backend>> = OnceLock::new();
+
+/// Picks the backend for the rest of the run: the simulator for a dry run,
+/// else the host's WireGuard.
+pub fn init(dry_run: bool) {
+    let backend: Box<dyn Backend> = if dry_run {
+        Box::<Simulator>::default()
+    } else {
+        Box::new(System)
+    };
+    let _ = BACKEND.set(backend);
+}
+
+pub fn get() -> &'static dyn Backend {
+    BACKEND.get_or_init(|| Box::new(System)).as_ref()
+}
+
+/// The host's WireGuard, configured over netlink, or with wg-quick where the
+/// kernel does not speak WireGuard, or the embedded userspace implementation
+/// for interfaces that ask for it, along with the firewall and traffic
+/// shaping that go with them.
+pub struct System;
+
+#[async_trait]
+impl Backend for System {
+    async fn up(&self, server: &Server) -> Result<(), String> {
+        if server.firewall == Firewall::Nftables {
+            nft::apply(server).await?;
+        }
+
+        let result = if server.backend == Kind::Userspace {
+            userspace::up(server, &hooks(server).await).await
+        } else if netlink::supported() {
+            netlink::up(server, &hooks(server).await).await
+        } else {
+            wg_quick(server, "up").await
+        };
+        if let Err(err) = result {
+            if server.firewall == Firewall::Nftables {
+                let _ = nft::remove(server).await;
+            }
+            return Err(err);
+        }
+
+        // Without its rate limits the interface does not run as configured,
+        // so it is taken down again rather than left up unshaped.
+        if let Err(err) = tc::apply(server).await {
+            let _ = self.down(server).await;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    async fn down(&self, server: &Server) -> Result<(), String> {
+        tc::remove(server).await;
+        if server.backend == Kind::Userspace {
+            userspace::down(server, &hooks(server).await).await?;
+        } else if netlink::supported() {
+            netlink::down(server, &hooks(server).await).await?;
+        } else {
+            wg_quick(server, "down").await?;
+        }
+
+        if server.firewall == Firewall::Nftables {
+            nft::remove(server).await?;
+        }
+        Ok(())
+    }
+
+    async fn sync(&self, server: &Server) -> Result<(), String> {
+        let mut errors = vec![];
+        if server.backend == Kind::Userspace {
+            errors.extend(userspace::configure(server).err());
+        } else if netlink::supported() {
+            errors.extend(netlink::configure(server, wireguard_control::Backend::Kernel).err());
+        } else {
+            errors.extend(sync_config(server).await.err());
+        }
+
+        if server.firewall == Firewall::Nftables {
+            errors.extend(nft::apply(server).await.err());
+        }
+        errors.extend(tc::apply(server).await.err());
+
+        // Syncing only touches the device, so routes for the extra networks
+        // behind peers have to be kept in place by hand, and taken away from
+        // peers that are suspended, expired or out of their schedule.
+        let now = Utc::now();
+        let (active, inactive): (Vec<_>, Vec<_>) =
+            server.peers.iter().partition(|peer| peer.active(now));
+        for peer in &active {
+            for cidr in &peer.allowed_ips {
+                route(&server.name, "replace", cidr).await;
+            }
+        }
+        for peer in &inactive {
+            for cidr in &peer.allowed_ips {
+                route(&server.name, "del", cidr).await;
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(errors.concat())
+    }
+
+    async fn remove_route(&self, name: &str, cidr: &IpNet) {
+        route(name, "del", cidr).await;
+    }
+
+    async fn interfaces(&self) -> Result<HashSet<String>, String> {
+        let mut status = userspace::interfaces();
+        if netlink::supported() {
+            match netlink::interfaces() {
+                Ok(kernel) => {
+                    status.extend(kernel);
+                    return Ok(status);
+                }
+                Err(err) => {
+                    eprintln!("Failed to list interfaces over netlink: {}", err);
+                }
+            }
+        }
+
+        let output = run("wg", &["show", "interfaces"]).await?;
+        let output = String::from_utf8_lossy(&output);
+        status.extend(output.split_whitespace().map(String::from));
+        Ok(status)
+    }
+
+    async fn stats(&self, name: &str) -> HashMap<String, PeerStats> {
+        if userspace::is_running(name) {
+            return netlink::dump(name, wireguard_control::Backend::Userspace);
+        }
+        if netlink::supported() {
+            return netlink::dump(name, wireguard_control::Backend::Kernel);
+        }
+
+        let output = match Command::new("wg")
+            .args(["show", name, "dump"])
+            .output()
+            .await
+        {
+            Ok(output) if output.status.success() => output,
+            _ => return HashMap::new(),
+        };
+        stats::parse(&String::from_utf8_lossy(&output.stdout), stats::now())
+    }
+
+    fn generate_keys(&self) -> (String, String) {
+        netlink::generate_keys()
+    }
+}
+
+/// Brings an interface up or down with wg-quick, for kernels without
+/// WireGuard support.
+async fn wg_quick(server: &Server, action: &str) -> Result<(), String> {
+    write_config(server).await?;
+    if let Err(err) = run("wg-quick", &[action, &server.path]).await {
+        metrics::WG_QUICK_FAILURES
+            .with_label_values(&[action])
+            .inc();
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Applies the config file to a running interface with `wg syncconf`, for
+/// kernels without WireGuard support.
+async fn sync_config(server: &Server) -> Result<(), String> {
+    write_config(server).await?;
+
+    let output = match run("wg-quick", &["strip", &server.path]).await {
+        Ok(output) => output,
+        Err(err) => {
+            metrics::WG_QUICK_FAILURES
+                .with_label_values(&["strip"])
+                .inc();
+            return Err(err);
+        }
+    };
+
+    let update_file_name = format!("/tmp/update_{}.conf", server.name);
+    tokio::fs::write(&update_file_name, output)
+        .await
+        .map_err(|err| format!("Failed to write {}: {}", update_file_name, err))?;
+
+    run("wg", &["syncconf", &server.name, &update_file_name]).await?;
+    Ok(())
+}
+
+/// Adds, replaces or deletes the route for a network through an interface.
+async fn route(name: &str, action: &str, cidr: &IpNet) {
+    let family = match cidr {
+        IpNet::V4(_) => "-4",
+        IpNet::V6(_) => "-6",
+    };
+    let cidr = cidr.to_string();
+    if let Err(err) = run("ip", &[family, "route", action, &cidr, "dev", name]).await {
+        // A route that is already gone is what deleting it is after.
+        if action == "del" && err.contains("No such process") {
+            return;
+        }
+        eprintln!("ip route {} {} dev {} failed: {}", action, cidr, name, err);
+    }
+}
+
+/// Runs a command, failing with what it wrote to stderr if it does not
+/// succeed.
+async fn run(program: &str, args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|err| format!("Failed to execute {}: {}", program, err))?;
+
+    if output.status.success() {
+        return Ok(output.stdout);
+    }
+    Err(String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+/// Writes the wg-quick config file for a server.
+async fn write_config(server: &Server) -> Result<(), String> {
+    let failed = |err| format!("Failed to write {}: {}", server.path, err);
+    let mut file = BufWriter::new(File::create(&server.path).await.map_err(failed)?);
+
+    file.write_all(b"[Interface]\n").await.map_err(failed)?;
+    file.write_all(format!("Address = {}\n", join(&server.addresses())).as_bytes())
+        .await
+        .map_err(failed)?;
+    file.write_all(format!("ListenPort = {}\n", server.port).as_bytes())
+        .await
+        .map_err(failed)?;
+    let prikey = masterkey::open(&server.prikey)?;
+    file.write_all(format!("PrivateKey = {}\n", prikey).as_bytes())
+        .await
+        .map_err(failed)?;
+
+    let hooks = hooks(server).await;
+    let hooks = [
+        ("PreUp", &hooks.pre_up),
+        ("PostUp", &hooks.post_up),
+        ("PreDown", &hooks.pre_down),
+        ("PostDown", &hooks.post_down),
+    ];
+    for (key, commands) in hooks {
+        for command in commands {
+            file.write_all(format!("{} = {}\n", key, command).as_bytes())
+                .await
+                .map_err(failed)?;
+        }
+    }
+    file.write_all(b"\n").await.map_err(failed)?;
+
+    let now = Utc::now();
+    for peer in server.peers.iter().filter(|peer| peer.active(now)) {
+        file.write_all(b"[Peer]\n").await.map_err(failed)?;
+        file.write_all(format!("PublicKey = {}\n", peer.pubkey).as_bytes())
+            .await
+            .map_err(failed)?;
+        file.write_all(format!("AllowedIPs = {}\n\n", join(&peer.routes())).as_bytes())
+            .await
+            .map_err(failed)?;
+    }
+
+    file.flush().await.map_err(failed)
+}
+
+/// The commands to run around bringing a server up and down: the iptables
+/// rules for its NAT policy, if iptables implements it, followed by its own
+/// hooks.
+async fn hooks(server: &Server) -> Hooks {
+    let (nat_up, nat_down) = match server.firewall {
+        Firewall::Iptables => nat::iptables(server).await,
+        Firewall::Nftables => (vec![], vec![]),
+    };
+    Hooks {
+        pre_up: server.hooks.pre_up.clone(),
+        post_up: nat_up
+            .into_iter()
+            .chain(server.hooks.post_up.clone())
+            .collect(),
+        pre_down: server.hooks.pre_down.clone(),
+        post_down: nat_down
+            .into_iter()
+            .chain(server.hooks.post_down.clone())
+            .collect(),
+    }
+}
@@ -0,0 +1,113 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::fmt;
+
+/// Why a request failed. Each kind maps to a status code and a stable code
+/// for clients to match on.
+#[derive(Debug)]
+pub enum Error {
+    /// No interface goes by the name or id.
+    InterfaceNotFound(String),
+    /// The interface has no peer by the name or id.
+    PeerNotFound(String),
+    /// Something else the request refers to does not exist.
+    NotFound(String),
+    /// The request is malformed or asks for something that cannot be.
+    BadRequest(String),
+    /// The request clashes with what is there, such as a name that is taken.
+    Conflict(String),
+    /// The host refused to put a change into effect.
+    Backend {
+        message: String,
+        /// What the backend reported, such as the stderr of wg-quick.
+        stderr: String,
+    },
+    Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+struct Body {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+}
+
+impl Error {
+    pub fn bad_request(message: impl Into<String>) -> Error {
+        Error::BadRequest(message.into())
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Error {
+        Error::Conflict(message.into())
+    }
+
+    /// Wraps what the backend reported when an action on an interface failed.
+    pub fn backend(action: &str, iface: &str) -> impl FnOnce(String) -> Error {
+        let message = format!("Failed to {} {}", action, iface);
+        move |stderr| Error::Backend { message, stderr }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::InterfaceNotFound(_) | Error::PeerNotFound(_) | Error::NotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::Backend { .. } | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::InterfaceNotFound(_) => "interface_not_found",
+            Error::PeerNotFound(_) => "peer_not_found",
+            Error::NotFound(_) => "not_found",
+            Error::BadRequest(_) => "bad_request",
+            Error::Conflict(_) => "conflict",
+            Error::Backend { .. } => "backend_failed",
+            Error::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InterfaceNotFound(iface) => write!(f, "No interface {}", iface),
+            Error::PeerNotFound(peer) => write!(f, "No peer {}", peer),
+            Error::NotFound(message)
+            | Error::BadRequest(message)
+            | Error::Conflict(message)
+            | Error::Internal(message) => f.write_str(message),
+            Error::Backend { message, stderr } => write!(f, "{}: {}", message, stderr.trim_end()),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        // The client is told what failed, but whoever runs the host has to
+        // see it too.
+        if status.is_server_error() {
+            eprintln!("{}", self);
+        }
+        let code = self.code();
+        let (message, stderr) = match self {
+            Error::Backend { message, stderr } => (message, Some(stderr)),
+            error => (error.to_string(), None),
+        };
+        let body = Body {
+            code,
+            message,
+            stderr,
+        };
+        (status, Json(body)).into_response()
+    }
+}
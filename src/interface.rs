@@ -1,176 +1,535 @@
+use crate::backend;
+use crate::error::Error;
+use crate::extract::{Json, Path};
+use crate::ipam;
+use crate::masterkey;
+use crate::nat::{Hooks, Nat};
+use crate::nft::Firewall;
 use crate::state::SharedState;
+use crate::stats::{self, PeerStats};
+use crate::storage::Change;
 use crate::wghelper::{Server, Wg};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
-use axum::Json;
-use axum::{extract::Path, Extension};
+use axum::Extension;
+use ipnet::{IpNet, Ipv6Net};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-/*
 #[derive(Debug, Deserialize)]
 pub struct UpdateInterfaceConf {
     name: Option<String>,
     address: Option<String>,
+    address6: Option<String>,
     port: Option<u16>,
-    enabled: Option<bool>,
-    ifup: Option<String>,
-    ifdown: Option<String>,
-    publickey: Option<String>,
+    endpoint: Option<String>,
+    nat: Option<Nat>,
+    hooks: Option<Hooks>,
+    backend: Option<backend::Kind>,
+    firewall: Option<Firewall>,
+    isolation: Option<bool>,
     privatekey: Option<String>,
+    #[serde(default)]
+    regenerate_keys: bool,
 }
-*/
 
 #[derive(Debug, Deserialize)]
 pub struct CreateServer {
     name: String,
     port: u16,
     cidr: String,
+    cidr6: Option<String>,
+    endpoint: Option<String>,
+    nat: Option<Nat>,
+    hooks: Option<Hooks>,
+    backend: Option<backend::Kind>,
+    firewall: Option<Firewall>,
+    #[serde(default)]
+    isolation: bool,
 }
 
 pub async fn start_server(
-    Path(server_id): Path<usize>,
+    Path(iface): Path<String>,
     Extension(state): Extension<SharedState>,
-) -> Result<StatusCode, StatusCode> {
-    let state = state.read().await;
-    if state.servers.get(server_id).is_some() && state.start(server_id).await.is_ok() {
-        return Ok(StatusCode::OK);
+) -> Result<StatusCode, Error> {
+    let state = state.write().await;
+    let server_id = state.server_id(&iface)?;
+    let name = &state.servers[server_id].name;
+    if Wg::server_status().await?.contains(name) {
+        return Err(Error::conflict(format!("{} is already running", name)));
     }
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    state
+        .start(server_id)
+        .await
+        .map_err(Error::backend("start", name))?;
+    Ok(StatusCode::OK)
 }
 
 pub async fn stop_server(
-    Path(server_id): Path<usize>,
+    Path(iface): Path<String>,
     Extension(state): Extension<SharedState>,
-) -> Result<StatusCode, StatusCode> {
-    let state = state.read().await;
-    if state.servers.get(server_id).is_some() && state.stop(server_id).await.is_ok() {
-        return Ok(StatusCode::OK);
+) -> Result<StatusCode, Error> {
+    let state = state.write().await;
+    let server_id = state.server_id(&iface)?;
+    let name = &state.servers[server_id].name;
+    if !Wg::server_status().await?.contains(name) {
+        return Err(Error::conflict(format!("{} is not running", name)));
     }
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    state
+        .stop(server_id)
+        .await
+        .map_err(Error::backend("stop", name))?;
+    Ok(StatusCode::OK)
 }
 
 pub async fn refresh_server(
-    Path(server_id): Path<usize>,
+    Path(iface): Path<String>,
     Extension(state): Extension<SharedState>,
-) -> Result<StatusCode, StatusCode> {
+) -> Result<StatusCode, Error> {
     let state = state.read().await;
-    if state.servers.get(server_id).is_some() {
-        state.hot_reload(server_id).await;
-        return Ok(StatusCode::OK);
+    let server_id = state.server_id(&iface)?;
+    let name = &state.servers[server_id].name;
+    if !Wg::server_status().await?.contains(name) {
+        return Err(Error::conflict(format!("{} is not running", name)));
     }
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    state.hot_reload(server_id).await?;
+    Ok(StatusCode::OK)
 }
 
-pub async fn get_servers(Extension(state): Extension<SharedState>) -> impl IntoResponse {
+pub async fn get_servers(
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, Error> {
     #[derive(Serialize)]
     struct Status {
+        id: Uuid,
         name: String,
         running: bool,
         address: String,
+        address6: Option<String>,
         peer_count: usize,
         port: u16,
+        endpoint: Option<String>,
+        isolation: bool,
     }
 
     let state = state.read().await;
-    let server_status = Wg::server_status().await;
+    let server_status = Wg::server_status().await?;
     let ifaces: Vec<Status> = state
         .servers
         .iter()
         .map(|server| Status {
+            id: server.id,
             name: server.name.clone(),
             running: server_status.contains(&server.name),
-            address: format!("{}/{}", server.address.replace('x', "0"), server.subnet),
+            address: server.address.trunc().to_string(),
+            address6: server.address6.map(|address6| address6.trunc().to_string()),
             peer_count: server.peers.len(),
             port: server.port,
+            endpoint: state.endpoint(server),
+            isolation: server.isolation,
+        })
+        .collect();
+    Ok(Json(ifaces))
+}
+
+pub async fn get_server_stats(
+    Path(iface): Path<String>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, Error> {
+    #[derive(Serialize)]
+    struct PeerEntry {
+        id: Uuid,
+        name: String,
+        #[serde(flatten)]
+        stats: PeerStats,
+    }
+
+    #[derive(Serialize)]
+    struct Stats {
+        running: bool,
+        online: usize,
+        rx_bytes: u64,
+        tx_bytes: u64,
+        peers: Vec<PeerEntry>,
+    }
+
+    let state = state.read().await;
+    let server_id = state.server_id(&iface)?;
+    let server = &state.servers[server_id];
+    let running = Wg::server_status().await?.contains(&server.name);
+    let mut stats = stats::dump(&server.name).await;
+
+    let peers: Vec<PeerEntry> = server
+        .peers
+        .iter()
+        .map(|peer| PeerEntry {
+            id: peer.id,
+            name: peer.name.clone(),
+            stats: stats.remove(&peer.pubkey).unwrap_or_default(),
         })
         .collect();
-    Json(ifaces)
+
+    Ok(Json(Stats {
+        running,
+        online: peers.iter().filter(|peer| peer.stats.online).count(),
+        rx_bytes: peers.iter().map(|peer| peer.stats.rx_bytes).sum(),
+        tx_bytes: peers.iter().map(|peer| peer.stats.tx_bytes).sum(),
+        peers,
+    }))
 }
 
 pub async fn create_server(
     Json(create_server): Json<CreateServer>,
     Extension(state): Extension<SharedState>,
-) -> Result<StatusCode, StatusCode> {
+) -> Result<StatusCode, Error> {
     let mut state = state.write().await;
-    state
-        .create(&create_server.name, &create_server.cidr, create_server.port)
-        .await;
+    if !Wg::valid_name(&create_server.name) {
+        return Err(Error::bad_request("Invalid interface name"));
+    }
+    if state.server_index(&create_server.name).is_some() {
+        return Err(Error::conflict(format!(
+            "{} already exists",
+            create_server.name
+        )));
+    }
+    if create_server.port == 0 {
+        return Err(Error::bad_request("Invalid port"));
+    }
+    if state
+        .servers
+        .iter()
+        .any(|other| other.port == create_server.port)
+    {
+        return Err(Error::conflict(format!(
+            "Port {} is taken",
+            create_server.port
+        )));
+    }
+    let address = ipam::parse_network(&create_server.cidr)
+        .ok_or_else(|| Error::bad_request("Invalid IPv4 network"))?;
+    // IPv6 is opt-in, as forwarding it opens up the whole host.
+    let address6 = create_server
+        .cidr6
+        .as_deref()
+        .map(parse_network6)
+        .transpose()?;
+    check_network(&state, None, &IpNet::V4(address))?;
+    if let Some(address6) = address6 {
+        check_network(&state, None, &IpNet::V6(address6))?;
+    }
+    if let Some(endpoint) = &create_server.endpoint {
+        if !Wg::valid_endpoint(endpoint) {
+            return Err(Error::bad_request("Invalid endpoint"));
+        }
+    }
+    let nat = create_server.nat.unwrap_or_default();
+    let hooks = create_server.hooks.unwrap_or_default();
+    if !valid_nat(&nat) {
+        return Err(Error::bad_request("Invalid egress interface"));
+    }
+    if !hooks.is_valid() {
+        return Err(Error::bad_request("Hooks must not contain line breaks"));
+    }
+
+    let server = state.create(&create_server.name, address, create_server.port);
+    server.address6 = address6;
+    server.endpoint = create_server.endpoint;
+    server.nat = nat;
+    server.hooks = hooks;
+    if let Some(backend) = create_server.backend {
+        server.backend = backend;
+    }
+    if let Some(firewall) = create_server.firewall {
+        server.firewall = firewall;
+    }
+    server.isolation = create_server.isolation;
+    let change = Change::Server(server.id);
 
-    Wg::dump_state(&state).await;
+    Wg::dump_state(&state, &[change]).await?;
     Ok(StatusCode::OK)
 }
 
 pub async fn get_server(
-    Path(server_id): Path<usize>,
+    Path(iface): Path<String>,
     Extension(state): Extension<SharedState>,
-) -> Result<Json<Server>, StatusCode> {
+) -> Result<Json<Server>, Error> {
     let state = state.read().await;
-    if let Some(server) = state.servers.get(server_id) {
-        Ok(Json(server.clone()))
-    } else {
-        Err(StatusCode::INTERNAL_SERVER_ERROR)
-    }
+    let server_id = state.server_id(&iface)?;
+    Ok(Json(state.servers[server_id].clone()))
 }
 
-/*
 pub async fn update_server(
-    Json(updated_json): Json<UpdateInterfaceConf>,
-    Path(server_id): Path<usize>,
+    Path(iface): Path<String>,
     Extension(state): Extension<SharedState>,
-) -> Result<StatusCode, StatusCode> {
+    Json(update): Json<UpdateInterfaceConf>,
+) -> Result<StatusCode, Error> {
     let mut state = state.write().await;
-    dbg!(&updated_json);
-    if state.interfaces.len() > id {
-        // name
-        if let Some(name) = updated_json.name {
-            state.interfaces[id].name = name;
+    let server_id = state.server_id(&iface)?;
+    let server = &state.servers[server_id];
+
+    let name = update.name.filter(|name| *name != server.name);
+    if let Some(name) = &name {
+        if !Wg::valid_name(name) {
+            return Err(Error::bad_request("Invalid interface name"));
         }
-        // address
-        if let Some(address) = updated_json.address {
-            state.interfaces[id].address = address;
+        if state.server_index(name).is_some() {
+            return Err(Error::conflict(format!("{} already exists", name)));
         }
-        // port
-        if let Some(port) = updated_json.port {
-            state.interfaces[id].port = port;
+    }
+
+    let too_small = || Error::bad_request("The network is too small for the peers");
+    let address = match update.address {
+        Some(address) => {
+            let address = ipam::parse_network(&address)
+                .ok_or_else(|| Error::bad_request("Invalid IPv4 network"))?;
+            let current: Vec<_> = server.peers.iter().map(|peer| Some(peer.address)).collect();
+            let peers = ipam::renumber(address, &current).ok_or_else(too_small)?;
+            Some((address, peers)).filter(|_| address != server.address)
         }
-        // enabled
-        if let Some(enabled) = updated_json.enabled {
-            state.interfaces[id].enabled = enabled;
+        None => None,
+    };
+
+    let address6 = match update.address6 {
+        Some(address6) => {
+            let address6 = parse_network6(&address6)?;
+            let current: Vec<_> = server.peers.iter().map(|peer| peer.address6).collect();
+            let peers = ipam::renumber(address6, &current).ok_or_else(too_small)?;
+            Some((address6, peers)).filter(|_| Some(address6) != server.address6)
         }
-        // ifup
-        if let Some(ifup) = updated_json.ifup {
-            state.interfaces[id].ifup = ifup;
+        None => None,
+    };
+
+    // The networks behind peers must stay outside of the interface's own.
+    let networks = [
+        address.as_ref().map(|(address, _)| IpNet::V4(*address)),
+        address6.as_ref().map(|(address6, _)| IpNet::V6(*address6)),
+    ];
+    for network in networks.iter().flatten() {
+        check_network(&state, Some(server.id), network)?;
+        let peer = server.peers.iter().find(|peer| {
+            peer.allowed_ips
+                .iter()
+                .any(|cidr| ipam::overlaps(cidr, network))
+        });
+        if let Some(peer) = peer {
+            return Err(Error::conflict(format!(
+                "{} overlaps the networks routed to {}",
+                network.trunc(),
+                peer.name
+            )));
         }
-        // ifdown
-        if let Some(ifdown) = updated_json.ifdown {
-            state.interfaces[id].ifdown = ifdown;
+    }
+
+    if let Some(port) = update.port {
+        if port == 0 {
+            return Err(Error::bad_request("Invalid port"));
         }
-        // publickey
-        if let Some(publickey) = updated_json.publickey {
-            state.interfaces[id].publickey = publickey;
+        let taken = state
+            .servers
+            .iter()
+            .any(|other| other.id != server.id && other.port == port);
+        if taken {
+            return Err(Error::conflict(format!("Port {} is taken", port)));
         }
-        // privatekey
-        if let Some(privatekey) = updated_json.privatekey {
-            state.interfaces[id].privatekey = privatekey;
+    }
+
+    // An empty endpoint falls back to the global one.
+    let endpoint = match update.endpoint {
+        Some(endpoint) if endpoint.is_empty() => Some(None),
+        Some(endpoint) if Wg::valid_endpoint(&endpoint) => Some(Some(endpoint)),
+        Some(_) => return Err(Error::bad_request("Invalid endpoint")),
+        None => None,
+    };
+
+    // Only the settings that differ count as changes, so that repeating the
+    // current value does not restart a running interface.
+    let nat = update.nat.filter(|nat| *nat != server.nat);
+    let hooks = update.hooks.filter(|hooks| *hooks != server.hooks);
+    let backend = update.backend.filter(|backend| *backend != server.backend);
+    let firewall = update
+        .firewall
+        .filter(|firewall| *firewall != server.firewall);
+    let isolation = update
+        .isolation
+        .filter(|isolation| *isolation != server.isolation);
+
+    // Only the nftables backend knows how to enforce ACLs, so leaving it
+    // would quietly lift them.
+    if firewall == Some(Firewall::Iptables) {
+        if let Some(peer) = server.peers.iter().find(|peer| !peer.acl.is_empty()) {
+            return Err(Error::conflict(format!(
+                "{} has an ACL, which needs the nftables firewall",
+                peer.name
+            )));
         }
-        wghelper::write_config(&state).await;
-        Ok(StatusCode::OK)
+    }
+
+    // With iptables, isolation is part of the wg-quick hooks as well, while
+    // nftables picks it up when its rules are reloaded.
+    let hooks_changed = nat.is_some()
+        || hooks.is_some()
+        || firewall.is_some()
+        || (isolation.is_some() && server.firewall == Firewall::Iptables);
+    if !nat.as_ref().is_none_or(valid_nat) {
+        return Err(Error::bad_request("Invalid egress interface"));
+    }
+    if !hooks.as_ref().is_none_or(Hooks::is_valid) {
+        return Err(Error::bad_request("Hooks must not contain line breaks"));
+    }
+
+    let keys = if update.regenerate_keys {
+        Some(Wg::get_keys())
+    } else if let Some(prikey) = update.privatekey {
+        let pubkey = Wg::pubkey(&prikey).map_err(Error::BadRequest)?;
+        Some((masterkey::seal(&prikey), pubkey))
     } else {
-        Err(StatusCode::INTERNAL_SERVER_ERROR)
+        None
+    };
+
+    // The name, address and implementation live outside of what `wg syncconf`
+    // can change, so a running interface has to be brought down and up again
+    // for those.
+    let running = Wg::server_status().await?.contains(&server.name);
+    let restart = running
+        && (name.is_some()
+            || address.is_some()
+            || address6.is_some()
+            || backend.is_some()
+            || hooks_changed);
+    if restart {
+        state
+            .stop(server_id)
+            .await
+            .map_err(Error::backend("stop", &iface))?;
+    }
+
+    let server = &mut state.servers[server_id];
+    let mut changes = vec![Change::Server(server.id)];
+    if let Some(name) = name {
+        let _ = tokio::fs::remove_file(&server.path).await;
+        server.path = format!("/tmp/{}.conf", name);
+        server.name = name;
+    }
+    if let Some(port) = update.port {
+        server.port = port;
+    }
+    if let Some(endpoint) = endpoint {
+        server.endpoint = endpoint;
+    }
+    if let Some(nat) = nat {
+        server.nat = nat;
+    }
+    if let Some(hooks) = hooks {
+        server.hooks = hooks;
+    }
+    if let Some(backend) = backend {
+        server.backend = backend;
+    }
+    if let Some(firewall) = firewall {
+        server.firewall = firewall;
+    }
+    if let Some(isolation) = isolation {
+        server.isolation = isolation;
+    }
+    if let Some((prikey, pubkey)) = keys {
+        server.prikey = prikey;
+        server.pubkey = pubkey;
     }
-    todo!()
+    // Renumbering the interface renumbers its peers along with it.
+    if address.is_some() || address6.is_some() {
+        changes.extend(server.peers.iter().map(|peer| Change::Peer(peer.id)));
+    }
+    if let Some((address, peers)) = address {
+        server.address = address;
+        for (peer, address) in server.peers.iter_mut().zip(peers) {
+            peer.address = address;
+        }
+    }
+    if let Some((address6, peers)) = address6 {
+        server.address6 = Some(address6);
+        for (peer, address6) in server.peers.iter_mut().zip(peers) {
+            peer.address6 = Some(address6);
+        }
+    }
+
+    Wg::dump_state(&state, &changes).await?;
+
+    if restart {
+        state
+            .start(server_id)
+            .await
+            .map_err(Error::backend("start", &state.servers[server_id].name))?;
+    } else if running {
+        state.hot_reload(server_id).await?;
+    }
+    Ok(StatusCode::OK)
+}
+
+/// Reads an IPv6 network, where `auto` stands for a newly generated unique
+/// local one.
+fn parse_network6(cidr6: &str) -> Result<Ipv6Net, Error> {
+    if cidr6 == "auto" {
+        return Ok(ipam::generate_ula());
+    }
+    ipam::parse_network6(cidr6).ok_or_else(|| Error::bad_request("Invalid IPv6 network"))
+}
+
+fn valid_nat(nat: &Nat) -> bool {
+    nat.egress.as_deref().is_none_or(Wg::valid_name)
+}
+
+/// Fails when a network for a server would share addresses with another
+/// server's network, or with what is routed to the other server's peers.
+fn check_network(state: &Wg, server_id: Option<Uuid>, network: &IpNet) -> Result<(), Error> {
+    for other in state
+        .servers
+        .iter()
+        .filter(|other| Some(other.id) != server_id)
+    {
+        if other
+            .addresses()
+            .iter()
+            .any(|address| ipam::overlaps(network, address))
+        {
+            return Err(Error::conflict(format!(
+                "{} overlaps the network of {}",
+                network.trunc(),
+                other.name
+            )));
+        }
+        let peer = other.peers.iter().find(|peer| {
+            peer.routes()
+                .iter()
+                .any(|route| ipam::overlaps(network, route))
+        });
+        if let Some(peer) = peer {
+            return Err(Error::conflict(format!(
+                "{} overlaps what is routed to {} on {}",
+                network.trunc(),
+                peer.name,
+                other.name
+            )));
+        }
+    }
+    Ok(())
 }
-*/
 
 pub async fn delete_server(
-    Path(server_id): Path<usize>,
+    Path(iface): Path<String>,
     Extension(state): Extension<SharedState>,
-) -> Result<StatusCode, StatusCode> {
+) -> Result<StatusCode, Error> {
     let mut state = state.write().await;
-    if state.servers.get(server_id).is_some() {
-        state.servers.remove(server_id);
-        Wg::dump_state(&state).await;
-        return Ok(StatusCode::OK);
+    let server_id = state.server_id(&iface)?;
+    // Once the interface is gone from the state nothing would bring it down,
+    // so a running one is stopped first.
+    let name = &state.servers[server_id].name;
+    if Wg::server_status().await?.contains(name) {
+        state
+            .stop(server_id)
+            .await
+            .map_err(Error::backend("stop", name))?;
     }
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    let server = state.servers.remove(server_id);
+    Wg::dump_state(&state, &[Change::Server(server.id)]).await?;
+    Ok(StatusCode::OK)
 }
@@ -0,0 +1,374 @@
+use crate::wghelper::Server;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use rand::Rng;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+/// What the pool needs to know about the networks of one address family.
+pub trait Network: Copy + PartialEq + Display {
+    type Addr: Copy + Ord + Display;
+
+    fn addr(&self) -> Self::Addr;
+    fn trunc(&self) -> Self;
+    fn with_addr(&self, address: Self::Addr) -> Self;
+    /// Whether the address may be given to a host, which leaves out the
+    /// network address and, for IPv4, the broadcast address.
+    fn is_host(&self, address: Self::Addr) -> bool;
+    fn hosts(&self) -> Box<dyn Iterator<Item = Self::Addr>>;
+}
+
+impl Network for Ipv4Net {
+    type Addr = Ipv4Addr;
+
+    fn addr(&self) -> Ipv4Addr {
+        Ipv4Net::addr(self)
+    }
+
+    fn trunc(&self) -> Self {
+        Ipv4Net::trunc(self)
+    }
+
+    fn with_addr(&self, address: Ipv4Addr) -> Self {
+        Ipv4Net::new(address, self.prefix_len()).unwrap()
+    }
+
+    fn is_host(&self, address: Ipv4Addr) -> bool {
+        self.contains(&address) && address != self.network() && address != self.broadcast()
+    }
+
+    fn hosts(&self) -> Box<dyn Iterator<Item = Ipv4Addr>> {
+        Box::new(Ipv4Net::hosts(self))
+    }
+}
+
+impl Network for Ipv6Net {
+    type Addr = Ipv6Addr;
+
+    fn addr(&self) -> Ipv6Addr {
+        Ipv6Net::addr(self)
+    }
+
+    fn trunc(&self) -> Self {
+        Ipv6Net::trunc(self)
+    }
+
+    fn with_addr(&self, address: Ipv6Addr) -> Self {
+        Ipv6Net::new(address, self.prefix_len()).unwrap()
+    }
+
+    fn is_host(&self, address: Ipv6Addr) -> bool {
+        self.contains(&address) && address != self.network()
+    }
+
+    fn hosts(&self) -> Box<dyn Iterator<Item = Ipv6Addr>> {
+        Box::new(Ipv6Net::hosts(self).skip(1))
+    }
+}
+
+/// The addresses of one interface's network that are free to hand out.
+pub struct Pool<N: Network> {
+    network: N,
+    used: BTreeSet<N::Addr>,
+}
+
+impl<N: Network> Pool<N> {
+    /// An empty pool in which only the server's own address is taken.
+    pub fn new(address: N) -> Pool<N> {
+        let mut used = BTreeSet::new();
+        used.insert(address.addr());
+        Pool {
+            network: address.trunc(),
+            used,
+        }
+    }
+
+    /// Takes the lowest free address, or returns `None` once the pool is exhausted.
+    pub fn allocate(&mut self) -> Option<N> {
+        let address = self
+            .network
+            .hosts()
+            .find(|address| !self.used.contains(address))?;
+        self.used.insert(address);
+        Some(self.network.with_addr(address))
+    }
+
+    /// Takes a specific address if it is a free host address of the network.
+    pub fn reserve(&mut self, address: N::Addr) -> Option<N> {
+        if !self.network.is_host(address) || !self.used.insert(address) {
+            return None;
+        }
+        Some(self.network.with_addr(address))
+    }
+}
+
+impl Pool<Ipv4Net> {
+    /// The IPv4 pool of a server with the addresses of all its peers taken,
+    /// except for the peer at `skip`.
+    pub fn v4(server: &Server, skip: Option<usize>) -> Pool<Ipv4Net> {
+        let mut pool = Pool::new(server.address);
+        for (index, peer) in server.peers.iter().enumerate() {
+            if Some(index) != skip {
+                pool.used.insert(peer.address.addr());
+            }
+        }
+        pool
+    }
+}
+
+impl Pool<Ipv6Net> {
+    /// The IPv6 pool of a server, if it has an IPv6 network at all.
+    pub fn v6(server: &Server, skip: Option<usize>) -> Option<Pool<Ipv6Net>> {
+        let mut pool = Pool::new(server.address6?);
+        for (index, peer) in server.peers.iter().enumerate() {
+            if let Some(address) = peer.address6.filter(|_| Some(index) != skip) {
+                pool.used.insert(address.addr());
+            }
+        }
+        Some(pool)
+    }
+}
+
+/// Moves the peers of a server to its new network, given their current
+/// addresses in order. A peer keeps its address if it is still a free host
+/// address there, so that the configs handed out to it keep working, and only
+/// the others get the lowest free ones. Returns `None` if the network is too
+/// small.
+pub fn renumber<N: Network>(address: N, current: &[Option<N>]) -> Option<Vec<N>> {
+    let mut pool = Pool::new(address);
+    let kept: Vec<Option<N>> = current
+        .iter()
+        .map(|peer| peer.and_then(|peer| pool.reserve(peer.addr())))
+        .collect();
+    kept.into_iter()
+        .map(|kept| kept.or_else(|| pool.allocate()))
+        .collect()
+}
+
+/// Parses the network of a new interface, such as `10.8.0.0/22`. The server
+/// takes the first host address unless the input already names one, as in
+/// `10.8.0.5/22`.
+pub fn parse_network(cidr: &str) -> Option<Ipv4Net> {
+    let address: Ipv4Net = cidr.parse().ok()?;
+    if address.prefix_len() > 30 {
+        return None;
+    }
+    if address.addr() == address.network() {
+        let first = Ipv4Net::hosts(&address).next()?;
+        return Some(address.with_addr(first));
+    }
+    Some(address).filter(|address| address.is_host(address.addr()))
+}
+
+/// Parses the IPv6 network of a new interface, such as `fd00:1::/64`, the
+/// same way as [`parse_network`].
+pub fn parse_network6(cidr: &str) -> Option<Ipv6Net> {
+    let address: Ipv6Net = cidr.parse().ok()?;
+    if address.prefix_len() > 126 {
+        return None;
+    }
+    if address.addr() == address.network() {
+        let first = Network::hosts(&address).next()?;
+        return Some(address.with_addr(first));
+    }
+    Some(address)
+}
+
+/// Generates a unique local /64 as described in RFC 4193, with a random
+/// global ID and the server on the first host address.
+pub fn generate_ula() -> Ipv6Net {
+    let id: [u8; 5] = rand::thread_rng().gen();
+    let address = Ipv6Addr::new(
+        0xfd00 | u16::from(id[0]),
+        u16::from_be_bytes([id[1], id[2]]),
+        u16::from_be_bytes([id[3], id[4]]),
+        0,
+        0,
+        0,
+        0,
+        1,
+    );
+    Ipv6Net::new(address, 64).unwrap()
+}
+
+/// Whether two networks share any address. Networks given in CIDR form
+/// either nest or do not overlap at all.
+pub fn overlaps(a: &IpNet, b: &IpNet) -> bool {
+    a.contains(&b.network()) || b.contains(&a.network())
+}
+
+/// Parses an address given with or without a prefix length.
+pub fn parse_address<A: std::str::FromStr>(address: &str) -> Option<A> {
+    address.split('/').next()?.parse().ok()
+}
+
+/// Reads a server address, upgrading the old `10.0.0.x` placeholder form where
+/// every `x` stood for one host octet and the server used `1` for each of them.
+pub fn deserialize_address<'de, D>(deserializer: D) -> Result<Ipv4Net, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let address = String::deserialize(deserializer)?;
+    let address = if address.contains('x') {
+        let hosts = address.chars().filter(|ch| *ch == 'x').count();
+        format!("{}/{}", address.replace('x', "1"), 32 - 8 * hosts)
+    } else {
+        address
+    };
+    address.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn v4(address: &str) -> Ipv4Net {
+        address.parse().unwrap()
+    }
+
+    fn ip(address: &str) -> Ipv4Addr {
+        address.parse().unwrap()
+    }
+
+    /// A server on 10.8.0.1/24 with peers on the given host numbers.
+    fn server(hosts: &[u8]) -> Server {
+        let peers: String = hosts
+            .iter()
+            .enumerate()
+            .map(|(index, host)| testutil::peer(&format!("peer{}", index), *host))
+            .collect();
+        testutil::server("wg0", &peers)
+    }
+
+    #[test]
+    fn allocate_skips_the_server_address() {
+        let mut pool = Pool::new(v4("10.8.0.1/24"));
+        assert_eq!(pool.allocate(), Some(v4("10.8.0.2/24")));
+        assert_eq!(pool.allocate(), Some(v4("10.8.0.3/24")));
+
+        let mut pool = Pool::new(v4("10.8.0.2/24"));
+        assert_eq!(pool.allocate(), Some(v4("10.8.0.1/24")));
+        assert_eq!(pool.allocate(), Some(v4("10.8.0.3/24")));
+    }
+
+    #[test]
+    fn allocate_reuses_freed_addresses() {
+        let mut pool = Pool::v4(&server(&[2, 4, 5]), None);
+        assert_eq!(pool.allocate(), Some(v4("10.8.0.3/24")));
+        assert_eq!(pool.allocate(), Some(v4("10.8.0.6/24")));
+
+        // The peer being changed does not hold on to its own address.
+        let mut pool = Pool::v4(&server(&[2, 3]), Some(0));
+        assert_eq!(pool.allocate(), Some(v4("10.8.0.2/24")));
+    }
+
+    #[test]
+    fn allocate_stops_when_the_pool_is_exhausted() {
+        let mut pool = Pool::new(v4("10.8.0.1/30"));
+        assert_eq!(pool.allocate(), Some(v4("10.8.0.2/30")));
+        assert_eq!(pool.allocate(), None);
+
+        let mut pool = Pool::v4(&server(&(2..=254).collect::<Vec<_>>()), None);
+        assert_eq!(pool.allocate(), None);
+    }
+
+    #[test]
+    fn reserve_takes_free_host_addresses_only() {
+        let mut pool = Pool::v4(&server(&[2]), None);
+        assert_eq!(pool.reserve(ip("10.8.0.10")), Some(v4("10.8.0.10/24")));
+        assert_eq!(pool.reserve(ip("10.8.0.10")), None);
+        assert_eq!(pool.reserve(ip("10.8.0.2")), None);
+        assert_eq!(pool.reserve(ip("10.8.0.1")), None);
+        assert_eq!(pool.reserve(ip("10.8.0.0")), None);
+        assert_eq!(pool.reserve(ip("10.8.0.255")), None);
+        assert_eq!(pool.reserve(ip("10.9.0.2")), None);
+        // A reserved address is not handed out again.
+        assert_eq!(pool.allocate(), Some(v4("10.8.0.3/24")));
+    }
+
+    #[test]
+    fn ipv6_pools_skip_the_network_address() {
+        let mut pool = Pool::new("fd00::1/64".parse::<Ipv6Net>().unwrap());
+        assert_eq!(pool.allocate(), Some("fd00::2/64".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_network_picks_the_server_address() {
+        assert_eq!(parse_network("10.8.0.0/22"), Some(v4("10.8.0.1/22")));
+        assert_eq!(parse_network("10.8.0.5/22"), Some(v4("10.8.0.5/22")));
+        assert_eq!(parse_network("10.8.0.0/30"), Some(v4("10.8.0.1/30")));
+        assert_eq!(parse_network("10.8.0.0/31"), None);
+        assert_eq!(parse_network("10.8.0.0/32"), None);
+        assert_eq!(parse_network("10.8.3.255/22"), None);
+        assert_eq!(parse_network("10.8.0.x"), None);
+    }
+
+    #[test]
+    fn renumber_fails_when_the_network_is_too_small() {
+        let peers = renumber(v4("10.9.0.1/30"), &[None]).unwrap();
+        assert_eq!(peers, vec![v4("10.9.0.2/30")]);
+        assert_eq!(renumber(v4("10.9.0.1/30"), &[None, None]), None);
+    }
+
+    #[test]
+    fn renumber_keeps_the_addresses_that_still_fit() {
+        let current = [
+            Some(v4("10.8.0.7/24")),
+            Some(v4("10.8.0.2/24")),
+            Some(v4("10.8.0.3/24")),
+        ];
+        // Growing the network keeps every peer where it is.
+        let peers = renumber(v4("10.8.0.1/22"), &current).unwrap();
+        assert_eq!(
+            peers,
+            vec![v4("10.8.0.7/22"), v4("10.8.0.2/22"), v4("10.8.0.3/22")]
+        );
+
+        // Only the peer on the server's new address moves, to the lowest
+        // address no one else keeps.
+        let peers = renumber(v4("10.8.0.2/24"), &current).unwrap();
+        assert_eq!(
+            peers,
+            vec![v4("10.8.0.7/24"), v4("10.8.0.1/24"), v4("10.8.0.3/24")]
+        );
+
+        // Peers outside of a new network are moved into it.
+        let peers = renumber(v4("10.9.0.1/24"), &current).unwrap();
+        assert_eq!(
+            peers,
+            vec![v4("10.9.0.2/24"), v4("10.9.0.3/24"), v4("10.9.0.4/24")]
+        );
+    }
+
+    #[test]
+    fn deserialize_address_upgrades_the_placeholder_form() {
+        #[derive(Deserialize)]
+        struct Address {
+            #[serde(deserialize_with = "deserialize_address")]
+            address: Ipv4Net,
+        }
+        let parse = |address: &str| {
+            toml::from_str::<Address>(&format!("address = \"{}\"", address))
+                .unwrap()
+                .address
+        };
+        assert_eq!(parse("10.0.0.x"), v4("10.0.0.1/24"));
+        assert_eq!(parse("10.0.x.x"), v4("10.0.1.1/16"));
+        assert_eq!(parse("10.x.x.x"), v4("10.1.1.1/8"));
+        assert_eq!(parse("10.8.0.1/22"), v4("10.8.0.1/22"));
+    }
+
+    #[test]
+    fn overlaps_matches_nested_networks() {
+        let net = |cidr: &str| cidr.parse::<IpNet>().unwrap();
+        assert!(overlaps(&net("10.0.0.0/8"), &net("10.8.0.5/32")));
+        assert!(overlaps(&net("10.8.0.5/32"), &net("10.0.0.0/8")));
+        assert!(overlaps(&net("0.0.0.0/0"), &net("192.168.1.0/24")));
+        assert!(!overlaps(&net("10.8.0.0/24"), &net("10.8.1.0/24")));
+        assert!(!overlaps(&net("10.8.0.0/24"), &net("fd00::/64")));
+    }
+}
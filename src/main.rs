@@ -2,19 +2,43 @@ use axum::{
     http::{header::AUTHORIZATION, Request, StatusCode},
     middleware::{self, Next},
     response::Response,
-    routing::get,
+    routing::{get, post},
     Extension, Router,
 };
-use state::SharedState;
+use state::{SharedState, SharedTraffic};
 use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use tower_http::cors::{Any, CorsLayer};
+use traffic::Traffic;
 use wghelper::Wg;
 
+mod acl;
+mod backend;
+mod error;
+mod extract;
 mod interface;
+mod ipam;
+mod masterkey;
+mod metrics;
+mod nat;
+mod netlink;
+mod nft;
 mod peer;
 mod peerconfig;
+mod persist;
+mod quota;
+mod schedule;
+mod settings;
+mod simulator;
+mod sqlite;
 mod state;
+mod stats;
+mod storage;
+mod tc;
+#[cfg(test)]
+mod testutil;
+mod traffic;
+mod userspace;
 mod wghelper;
 
 async fn auth<T>(req: Request<T>, next: Next<T>) -> Result<Response, StatusCode> {
@@ -24,16 +48,21 @@ async fn auth<T>(req: Request<T>, next: Next<T>) -> Result<Response, StatusCode>
         .and_then(|header| header.to_str().ok());
 
     match headers {
-        Some(auth) if auth == "WireGuardGui" => Ok(next.run(req).await),
+        Some("WireGuardGui") => Ok(next.run(req).await),
         _ => Err(StatusCode::UNAUTHORIZED),
     }
 }
 
-#[tokio::main()]
-async fn main() {
-    let interface_conf: Wg = Wg::read_state();
-    let shared_state: SharedState = Arc::new(RwLock::new(interface_conf));
+/// Gives up on startup errors that need someone to look at the host.
+fn or_exit<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    })
+}
 
+/// The routes of the API, all but `/login` behind the authorization check.
+fn app(shared_state: SharedState, shared_traffic: SharedTraffic) -> Router {
     let cors = CorsLayer::new()
         .allow_methods(Any)
         .allow_origin(Any)
@@ -42,6 +71,11 @@ async fn main() {
     let open_routes = Router::new().route("/login", get(|| async {}));
 
     let protected_routes = Router::new()
+        .route("/metrics", get(metrics::get_metrics))
+        .route(
+            "/settings",
+            get(settings::get_settings).patch(settings::update_settings),
+        )
         .route(
             "/interface",
             get(interface::get_servers).post(interface::create_server),
@@ -49,12 +83,18 @@ async fn main() {
         .route(
             "/interface/:iface",
             get(interface::get_server)
-                //.patch(interface::update_server)
+                .patch(interface::update_server)
                 .delete(interface::delete_server),
         )
         .route("/interface/:iface/start", get(interface::start_server))
         .route("/interface/:iface/stop", get(interface::stop_server))
         .route("/interface/:iface/refresh", get(interface::refresh_server))
+        .route("/interface/:iface/stats", get(interface::get_server_stats))
+        .route(
+            "/interface/:iface/traffic",
+            get(traffic::get_server_traffic),
+        )
+        .route("/interface/:iface/archive", get(schedule::get_archive))
         .route(
             "/interface/:iface/peer",
             get(peer::get_peers).post(peer::create_peer),
@@ -62,20 +102,102 @@ async fn main() {
         .route(
             "/interface/:iface/peer/:peer",
             get(peer::get_peer)
-                //.patch(peer::update_peer)
+                .patch(peer::update_peer)
                 .delete(peer::delete_peer),
         )
+        .route(
+            "/interface/:iface/peer/:peer/stats",
+            get(peer::get_peer_stats),
+        )
+        .route(
+            "/interface/:iface/peer/:peer/traffic",
+            get(traffic::get_peer_traffic),
+        )
+        .route(
+            "/interface/:iface/peer/:peer/suspend",
+            get(peer::suspend_peer),
+        )
+        .route(
+            "/interface/:iface/peer/:peer/resume",
+            get(peer::resume_peer),
+        )
+        .route(
+            "/interface/:iface/peer/:peer/quota",
+            get(quota::get_quota)
+                .put(quota::set_quota)
+                .delete(quota::delete_quota),
+        )
+        .route(
+            "/interface/:iface/peer/:peer/quota/override",
+            post(quota::create_override),
+        )
+        .route(
+            "/interface/:iface/peer/:peer/acl",
+            get(acl::get_acl).post(acl::create_rule),
+        )
+        .route(
+            "/interface/:iface/peer/:peer/acl/:rule",
+            get(acl::get_rule)
+                .put(acl::update_rule)
+                .delete(acl::delete_rule),
+        )
         .route(
             "/interface/:iface/peer/:peer/config",
             get(peerconfig::get_config),
         )
+        .route_layer(middleware::from_fn(metrics::track))
         .layer(Extension(shared_state))
+        .layer(Extension(shared_traffic))
         .layer(middleware::from_fn(auth));
 
-    let app = Router::new()
+    Router::new()
         .merge(open_routes)
         .merge(protected_routes)
-        .layer(cors);
+        .layer(cors)
+}
+
+#[tokio::main()]
+async fn main() {
+    // Runs against a simulated WireGuard and keeps the state in memory, for
+    // trying out the API without root.
+    let dry_run = std::env::args().any(|arg| arg == "--dry-run");
+    backend::init(dry_run);
+
+    or_exit(masterkey::init());
+    let sqlite = std::env::args().any(|arg| arg == "--sqlite");
+    match std::env::args().nth(1).as_deref() {
+        // `rest-wg migrate` copies the TOML state into a new SQLite database.
+        Some("migrate") => {
+            or_exit(storage::migrate());
+            return;
+        }
+        // `rest-wg rekey` encrypts the private keys with a new master key.
+        Some("rekey") => {
+            or_exit(storage::init(dry_run, sqlite));
+            or_exit(Wg::rekey());
+            return;
+        }
+        _ => {}
+    }
+    or_exit(storage::init(dry_run, sqlite));
+    let mut interface_conf: Wg = or_exit(Wg::read_state());
+    if interface_conf.endpoint.as_deref() == Some("auto") {
+        interface_conf.detected_endpoint = Wg::detect_endpoint().await;
+    }
+    let shared_state: SharedState = Arc::new(RwLock::new(interface_conf));
+    let traffic = if dry_run {
+        Traffic::default()
+    } else {
+        or_exit(Traffic::read())
+    };
+    let shared_traffic: SharedTraffic = Arc::new(Mutex::new(traffic));
+    tokio::spawn(schedule::run(shared_state.clone()));
+    tokio::spawn(traffic::sample(
+        shared_state.clone(),
+        shared_traffic.clone(),
+    ));
+
+    let app = app(shared_state, shared_traffic);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
     axum::Server::bind(&addr)
@@ -83,3 +205,324 @@ async fn main() {
         .await
         .unwrap();
 }
+
+/// The API end to end, against the simulator and a state kept in memory.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Method;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    /// The simulator is shared by every test, so each one uses interface
+    /// names of its own.
+    fn setup() -> Router {
+        backend::init(true);
+        storage::init(true, false).unwrap();
+        let state: SharedState = Arc::new(RwLock::new(Wg::default()));
+        let traffic: SharedTraffic = Arc::new(Mutex::new(Traffic::default()));
+        app(state, traffic)
+    }
+
+    async fn call(
+        app: &Router,
+        method: Method,
+        uri: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, "WireGuardGui")
+            .header("content-type", "application/json")
+            .body(match body {
+                Some(body) => Body::from(body.to_string()),
+                None => Body::empty(),
+            })
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    async fn peer_count(name: &str) -> usize {
+        backend::get().stats(name).await.len()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn interfaces_start_and_stop() {
+        let app = setup();
+        let create = json!({"name": "sim0", "cidr": "10.90.0.0/24", "port": 51900});
+        let (status, _) = call(&app, Method::POST, "/interface", Some(create.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&app, Method::POST, "/interface", Some(create)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = call(&app, Method::GET, "/interface/sim0/stop", None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _) = call(&app, Method::GET, "/interface/sim0/start", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&app, Method::GET, "/interface/sim0/start", None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (_, servers) = call(&app, Method::GET, "/interface", None).await;
+        assert_eq!(servers[0]["name"], "sim0");
+        assert_eq!(servers[0]["running"], true);
+
+        let (status, _) = call(&app, Method::GET, "/interface/sim0/stop", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, servers) = call(&app, Method::GET, "/interface", None).await;
+        assert_eq!(servers[0]["running"], false);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn running_interfaces_follow_their_peers() {
+        let app = setup();
+        let create = json!({"name": "sim1", "cidr": "10.91.0.0/24", "port": 51901});
+        call(&app, Method::POST, "/interface", Some(create)).await;
+        call(&app, Method::GET, "/interface/sim1/start", None).await;
+
+        for name in ["alice", "bob"] {
+            let (status, _) = call(
+                &app,
+                Method::POST,
+                "/interface/sim1/peer",
+                Some(json!({"name": name})),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = call(&app, Method::GET, "/interface/sim1/refresh", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(peer_count("sim1").await, 2);
+
+        let (status, _) = call(&app, Method::GET, "/interface/sim1/peer/bob/suspend", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(peer_count("sim1").await, 1);
+        let (status, _) = call(&app, Method::GET, "/interface/sim1/peer/bob/resume", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(peer_count("sim1").await, 2);
+
+        let (status, stats) = call(&app, Method::GET, "/interface/sim1/stats", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stats["running"], true);
+        assert_eq!(stats["peers"].as_array().unwrap().len(), 2);
+        assert_eq!(stats["online"], 0);
+    }
+
+    async fn addresses(app: &Router, iface: &str) -> Vec<String> {
+        let (_, peers) = call(
+            app,
+            Method::GET,
+            &format!("/interface/{}/peer", iface),
+            None,
+        )
+        .await;
+        let peers = peers.as_array().unwrap();
+        peers
+            .iter()
+            .map(|peer| peer["address"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn running_interfaces_are_renamed_and_renumbered() {
+        let app = setup();
+        let create = json!({"name": "sim4", "cidr": "10.94.0.0/24", "port": 51904});
+        call(&app, Method::POST, "/interface", Some(create)).await;
+        call(&app, Method::GET, "/interface/sim4/start", None).await;
+        let alice = json!({"name": "alice"});
+        call(&app, Method::POST, "/interface/sim4/peer", Some(alice)).await;
+        let bob = json!({"name": "bob", "address": "10.94.0.50"});
+        call(&app, Method::POST, "/interface/sim4/peer", Some(bob)).await;
+        call(&app, Method::GET, "/interface/sim4/refresh", None).await;
+
+        // Growing the network keeps every peer's address.
+        let update = json!({"name": "sim5", "address": "10.94.0.1/22"});
+        let (status, _) = call(&app, Method::PATCH, "/interface/sim4", Some(update)).await;
+        assert_eq!(status, StatusCode::OK);
+        let running = backend::get().interfaces().await.unwrap();
+        assert!(running.contains("sim5") && !running.contains("sim4"));
+        assert_eq!(peer_count("sim5").await, 2);
+        assert_eq!(
+            addresses(&app, "sim5").await,
+            ["10.94.0.2/22", "10.94.0.50/22"]
+        );
+
+        // Moving to another network moves the peers along.
+        let update = json!({"address": "10.95.0.0/24"});
+        let (status, _) = call(&app, Method::PATCH, "/interface/sim5", Some(update)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(peer_count("sim5").await, 2);
+        assert_eq!(
+            addresses(&app, "sim5").await,
+            ["10.95.0.2/24", "10.95.0.3/24"]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn peers_are_changed_on_a_running_interface() {
+        let app = setup();
+        let create = json!({"name": "sim6", "cidr": "10.96.0.0/24", "port": 51906});
+        call(&app, Method::POST, "/interface", Some(create)).await;
+        call(&app, Method::GET, "/interface/sim6/start", None).await;
+        for name in ["alice", "bob"] {
+            let peer = json!({"name": name});
+            call(&app, Method::POST, "/interface/sim6/peer", Some(peer)).await;
+        }
+        call(&app, Method::GET, "/interface/sim6/refresh", None).await;
+        let patch = |peer: &str, update: Value| {
+            let uri = format!("/interface/sim6/peer/{}", peer);
+            let app = app.clone();
+            async move { call(&app, Method::PATCH, &uri, Some(update)).await.0 }
+        };
+        let get = |peer: &str| {
+            let uri = format!("/interface/sim6/peer/{}", peer);
+            let app = app.clone();
+            async move { call(&app, Method::GET, &uri, None).await }
+        };
+
+        assert_eq!(patch("bob", json!({"name": "carol"})).await, StatusCode::OK);
+        assert_eq!(get("bob").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get("carol").await.0, StatusCode::OK);
+
+        let address = json!({"address": "10.96.0.40"});
+        assert_eq!(patch("carol", address).await, StatusCode::OK);
+        assert_eq!(get("carol").await.1["address"], "10.96.0.40/24");
+        let taken = json!({"address": "10.96.0.2"});
+        assert_eq!(patch("carol", taken).await, StatusCode::BAD_REQUEST);
+
+        let allowed_ips = json!({"allowed_ips": ["192.168.60.0/24"]});
+        assert_eq!(patch("alice", allowed_ips).await, StatusCode::OK);
+        let overlap = json!({"allowed_ips": ["192.168.60.128/25"]});
+        assert_eq!(patch("carol", overlap).await, StatusCode::CONFLICT);
+
+        // A new key replaces the old one on the interface, and the other
+        // peer stays on it all along.
+        let alice = get("alice").await.1["pubkey"].clone();
+        let old = get("carol").await.1["pubkey"].clone();
+        let regenerate = json!({"regenerate_keys": true});
+        assert_eq!(patch("carol", regenerate).await, StatusCode::OK);
+        let new = get("carol").await.1["pubkey"].clone();
+        assert_ne!(new, old);
+        let keys = backend::get().stats("sim6").await;
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains_key(alice.as_str().unwrap()));
+        assert!(keys.contains_key(new.as_str().unwrap()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deleting_a_peer_takes_it_off_a_running_interface() {
+        let app = setup();
+        let create = json!({"name": "sim7", "cidr": "10.97.0.0/24", "port": 51907});
+        call(&app, Method::POST, "/interface", Some(create)).await;
+        call(&app, Method::GET, "/interface/sim7/start", None).await;
+        for name in ["alice", "bob"] {
+            let peer = json!({"name": name});
+            call(&app, Method::POST, "/interface/sim7/peer", Some(peer)).await;
+        }
+        call(&app, Method::GET, "/interface/sim7/refresh", None).await;
+        assert_eq!(peer_count("sim7").await, 2);
+
+        let uri = "/interface/sim7/peer/bob";
+        let (status, _) = call(&app, Method::DELETE, uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(peer_count("sim7").await, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn networks_of_interfaces_do_not_overlap() {
+        let app = setup();
+        let create = json!({"name": "sim8", "cidr": "10.98.0.0/24", "port": 51908});
+        call(&app, Method::POST, "/interface", Some(create)).await;
+        let peer = json!({"name": "alice"});
+        call(&app, Method::POST, "/interface/sim8/peer", Some(peer)).await;
+        let allowed_ips = json!({"allowed_ips": ["10.99.5.0/24"]});
+        let uri = "/interface/sim8/peer/alice";
+        call(&app, Method::PATCH, uri, Some(allowed_ips)).await;
+
+        for cidr in ["10.98.0.0/16", "10.99.0.0/16"] {
+            let create = json!({"name": "sim9", "cidr": cidr, "port": 51909});
+            let (status, _) = call(&app, Method::POST, "/interface", Some(create)).await;
+            assert_eq!(status, StatusCode::CONFLICT);
+        }
+        let create = json!({"name": "sim9", "cidr": "10.100.0.0/24", "port": 51909});
+        let (status, _) = call(&app, Method::POST, "/interface", Some(create)).await;
+        assert_eq!(status, StatusCode::OK);
+        let update = json!({"address": "10.98.0.0/25"});
+        let (status, _) = call(&app, Method::PATCH, "/interface/sim9", Some(update)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ipv6_is_only_set_up_on_request() {
+        let app = setup();
+        let create = json!({"name": "sim10", "cidr": "10.101.0.0/24", "port": 51910});
+        call(&app, Method::POST, "/interface", Some(create)).await;
+        let (_, server) = call(&app, Method::GET, "/interface/sim10", None).await;
+        assert!(server["address6"].is_null());
+
+        let update = json!({"address6": "auto"});
+        let (status, _) = call(&app, Method::PATCH, "/interface/sim10", Some(update)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, server) = call(&app, Method::GET, "/interface/sim10", None).await;
+        assert!(server["address6"].as_str().unwrap().starts_with("fd"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deleting_a_running_interface_stops_it() {
+        let app = setup();
+        let create = json!({"name": "sim2", "cidr": "10.92.0.0/24", "port": 51902});
+        call(&app, Method::POST, "/interface", Some(create)).await;
+        call(&app, Method::GET, "/interface/sim2/start", None).await;
+
+        let (status, _) = call(&app, Method::DELETE, "/interface/sim2", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!backend::get().interfaces().await.unwrap().contains("sim2"));
+        let (status, error) = call(&app, Method::GET, "/interface/sim2", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(error["code"].is_string());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn malformed_requests_get_json_errors() {
+        let app = setup();
+        let (status, error) =
+            call(&app, Method::POST, "/interface", Some(json!({"name": 1}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["code"], "bad_request");
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/interface")
+            .header(AUTHORIZATION, "WireGuardGui")
+            .body(Body::from("{"))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let (status, error) = call(
+            &app,
+            Method::GET,
+            "/interface/sim3/traffic?period=week",
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["code"], "bad_request");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_need_authorization() {
+        let app = setup();
+        let request = Request::builder()
+            .uri("/interface")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
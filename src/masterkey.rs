@@ -0,0 +1,116 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::sync::OnceLock;
+
+/// Marks a private key that is stored encrypted, followed by the base64 of
+/// the nonce and the ciphertext.
+const PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+
+/// The name of the master key among the systemd credentials.
+const CREDENTIAL: &str = "rest-wg-master-key";
+
+static MASTER_KEY: OnceLock<Option<ChaCha20Poly1305>> = OnceLock::new();
+
+/// Reads the master key that private keys are encrypted with, if there is
+/// one. It is the base64 of 32 random bytes, taken from `REST_WG_MASTER_KEY`,
+/// from the file `REST_WG_MASTER_KEY_FILE` names or from the
+/// `rest-wg-master-key` systemd credential, in that order.
+pub fn init() -> Result<(), String> {
+    let cipher = read("REST_WG_MASTER_KEY", Some(CREDENTIAL))?;
+    let _ = MASTER_KEY.set(cipher);
+    Ok(())
+}
+
+/// Reads the key that `rekey` encrypts with, from `REST_WG_NEW_MASTER_KEY` or
+/// the file `REST_WG_NEW_MASTER_KEY_FILE` names.
+pub fn new_key() -> Result<ChaCha20Poly1305, String> {
+    read("REST_WG_NEW_MASTER_KEY", None)?.ok_or_else(|| {
+        "Set REST_WG_NEW_MASTER_KEY or REST_WG_NEW_MASTER_KEY_FILE to the new master key".into()
+    })
+}
+
+/// Encrypts a private key for storing, if there is a master key.
+pub fn seal(prikey: &str) -> String {
+    match master_key() {
+        Some(cipher) => seal_with(cipher, prikey),
+        None => prikey.into(),
+    }
+}
+
+pub fn seal_with(cipher: &ChaCha20Poly1305, prikey: &str) -> String {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut sealed = nonce.to_vec();
+    sealed.extend(cipher.encrypt(&nonce, prikey.as_bytes()).unwrap());
+    format!("{}{}", PREFIX, STANDARD.encode(sealed))
+}
+
+/// Decrypts a stored private key for writing it into a config. Keys stored
+/// in plaintext are returned as they are.
+pub fn open(stored: &str) -> Result<String, String> {
+    let sealed = match stored.strip_prefix(PREFIX) {
+        Some(sealed) => sealed,
+        None => return Ok(stored.into()),
+    };
+    let cipher = master_key().ok_or("the private key is encrypted, but no master key is set")?;
+    let sealed = STANDARD
+        .decode(sealed)
+        .ok()
+        .filter(|sealed| sealed.len() > NONCE_LEN)
+        .ok_or("the encrypted private key is malformed")?;
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .ok()
+        .and_then(|prikey| String::from_utf8(prikey).ok())
+        .ok_or_else(|| {
+            "the master key does not decrypt the private key, is it the right one?".into()
+        })
+}
+
+/// Checks that a stored private key can be decrypted, and encrypts it if it
+/// is in plaintext while there is a master key. Returns whether it changed.
+pub fn reseal(stored: &mut String) -> Result<bool, String> {
+    open(stored)?;
+    if master_key().is_none() || stored.starts_with(PREFIX) {
+        return Ok(false);
+    }
+    *stored = seal(stored);
+    Ok(true)
+}
+
+fn master_key() -> Option<&'static ChaCha20Poly1305> {
+    MASTER_KEY.get().and_then(Option::as_ref)
+}
+
+/// Reads a key from an env var, the file named by the same var with a
+/// `_FILE` suffix or a systemd credential.
+fn read(var: &str, credential: Option<&str>) -> Result<Option<ChaCha20Poly1305>, String> {
+    let file_var = format!("{}_FILE", var);
+    let (source, key) = if let Ok(key) = std::env::var(var) {
+        (var.to_string(), key)
+    } else if let Ok(path) = std::env::var(&file_var) {
+        let key = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+        (path, key)
+    } else {
+        let path = match (std::env::var("CREDENTIALS_DIRECTORY"), credential) {
+            (Ok(dir), Some(credential)) => format!("{}/{}", dir, credential),
+            _ => return Ok(None),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(key) => (path, key),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(format!("Failed to read {}: {}", path, err)),
+        }
+    };
+
+    let key = STANDARD
+        .decode(key.trim())
+        .ok()
+        .filter(|key| key.len() == 32)
+        .ok_or_else(|| format!("{} does not hold the base64 of a 32 byte key", source))?;
+    Ok(Some(ChaCha20Poly1305::new(Key::from_slice(&key))))
+}
@@ -0,0 +1,217 @@
+use crate::{error::Error, state::SharedState, stats, wghelper::Wg};
+use axum::{
+    extract::MatchedPath,
+    http::{header, HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use prometheus::{
+    core::Collector, Encoder, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounterVec,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::{collections::HashSet, sync::LazyLock, time::Instant};
+
+/// Metrics about rest-wg itself, which live as long as the process.
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("rest_wg_http_requests_total", "HTTP requests handled."),
+            &["method", "route", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+static HTTP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "rest_wg_http_request_duration_seconds",
+                "Time taken to handle HTTP requests.",
+            ),
+            &["method", "route"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static WG_QUICK_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "rest_wg_wg_quick_failures_total",
+                "wg-quick invocations that failed.",
+            ),
+            &["action"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static STATE_SAVE_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "rest_wg_state_save_duration_seconds",
+            "Time taken to write the state file.",
+        ))
+        .unwrap(),
+    )
+});
+
+fn register<C: Collector + Clone + 'static>(collector: C) -> C {
+    REGISTRY.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+/// Counts and times every request by the route it matched, so that paths
+/// with different interface and peer names end up in the same series.
+pub async fn track<T>(req: Request<T>, next: Next<T>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let method = req.method().to_string();
+    let start = Instant::now();
+
+    let response = next.run(req).await;
+
+    HTTP_DURATION
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    response
+}
+
+pub async fn get_metrics(
+    Extension(state): Extension<SharedState>,
+) -> Result<(HeaderMap, String), Error> {
+    let running = Wg::server_status().await?;
+    let registry = wireguard_metrics(&state, &running)
+        .await
+        .map_err(|err| Error::Internal(err.to_string()))?;
+
+    // Touch the process metrics so they show up before they are first used.
+    LazyLock::force(&STATE_SAVE_DURATION);
+    WG_QUICK_FAILURES.with_label_values(&["up"]);
+    WG_QUICK_FAILURES.with_label_values(&["down"]);
+    WG_QUICK_FAILURES.with_label_values(&["strip"]);
+
+    let mut families = registry.gather();
+    families.extend(REGISTRY.gather());
+    let mut output = vec![];
+    let encoder = TextEncoder::new();
+    encoder
+        .encode(&families, &mut output)
+        .map_err(|err| Error::Internal(err.to_string()))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, encoder.format_type().parse().unwrap());
+    Ok((
+        headers,
+        String::from_utf8(output).map_err(|err| Error::Internal(err.to_string()))?,
+    ))
+}
+
+/// Builds the interface and peer metrics from what `wg` reports right now.
+/// They go into a registry of their own on every scrape, so interfaces and
+/// peers that were deleted do not linger.
+async fn wireguard_metrics(
+    state: &SharedState,
+    running: &HashSet<String>,
+) -> prometheus::Result<Registry> {
+    let registry = Registry::new();
+    let interface_up = IntGaugeVec::new(
+        Opts::new("rest_wg_interface_up", "Whether the interface is running."),
+        &["interface"],
+    )?;
+    let interface_peers = IntGaugeVec::new(
+        Opts::new(
+            "rest_wg_interface_peers",
+            "Peers configured on the interface.",
+        ),
+        &["interface"],
+    )?;
+    let interface_port = IntGaugeVec::new(
+        Opts::new(
+            "rest_wg_interface_listen_port",
+            "UDP port the interface listens on.",
+        ),
+        &["interface"],
+    )?;
+    let peer_labels = ["interface", "peer", "id"];
+    let peer_rx = IntCounterVec::new(
+        Opts::new(
+            "rest_wg_peer_receive_bytes_total",
+            "Bytes received from the peer.",
+        ),
+        &peer_labels,
+    )?;
+    let peer_tx = IntCounterVec::new(
+        Opts::new(
+            "rest_wg_peer_transmit_bytes_total",
+            "Bytes sent to the peer.",
+        ),
+        &peer_labels,
+    )?;
+    let peer_handshake = GaugeVec::new(
+        Opts::new(
+            "rest_wg_peer_last_handshake_seconds",
+            "Seconds since the latest handshake with the peer.",
+        ),
+        &peer_labels,
+    )?;
+    let peer_enabled = IntGaugeVec::new(
+        Opts::new("rest_wg_peer_enabled", "Whether the peer is enabled."),
+        &peer_labels,
+    )?;
+
+    let now = stats::now();
+    let state = state.read().await;
+    for server in &state.servers {
+        let iface = server.name.as_str();
+        let up = running.contains(&server.name);
+        interface_up.with_label_values(&[iface]).set(up.into());
+        interface_peers
+            .with_label_values(&[iface])
+            .set(server.peers.len() as i64);
+        interface_port
+            .with_label_values(&[iface])
+            .set(server.port.into());
+
+        let dump = if up {
+            stats::dump(iface).await
+        } else {
+            Default::default()
+        };
+        for peer in &server.peers {
+            let id = peer.id.to_string();
+            let labels = [iface, peer.name.as_str(), id.as_str()];
+            peer_enabled
+                .with_label_values(&labels)
+                .set(peer.enabled.into());
+            let stats = dump.get(&peer.pubkey).cloned().unwrap_or_default();
+            peer_rx.with_label_values(&labels).inc_by(stats.rx_bytes);
+            peer_tx.with_label_values(&labels).inc_by(stats.tx_bytes);
+            if let Some(handshake) = stats.latest_handshake {
+                peer_handshake
+                    .with_label_values(&labels)
+                    .set(now.saturating_sub(handshake) as f64);
+            }
+        }
+    }
+
+    registry.register(Box::new(interface_up))?;
+    registry.register(Box::new(interface_peers))?;
+    registry.register(Box::new(interface_port))?;
+    registry.register(Box::new(peer_rx))?;
+    registry.register(Box::new(peer_tx))?;
+    registry.register(Box::new(peer_handshake))?;
+    registry.register(Box::new(peer_enabled))?;
+    Ok(registry)
+}
@@ -0,0 +1,117 @@
+use crate::wghelper::Server;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+/// Commands wg-quick runs around bringing an interface up and down. `%i`
+/// stands for the interface name.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Hooks {
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub pre_up: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub post_up: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub pre_down: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub post_down: Vec<String>,
+}
+
+impl Hooks {
+    /// Each hook ends up on a line of its own in the config file.
+    pub fn is_valid(&self) -> bool {
+        [&self.pre_up, &self.post_up, &self.pre_down, &self.post_down]
+            .iter()
+            .flat_map(|hooks| hooks.iter())
+            .all(|hook| !hook.contains(['\n', '\r']))
+    }
+}
+
+/// How traffic from the peers leaves the host.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Nat {
+    /// The uplink to masquerade behind, taken from the default route if unset.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub egress: Option<String>,
+    #[serde(default = "enabled")]
+    pub masquerade: bool,
+    #[serde(default = "enabled")]
+    pub forward: bool,
+}
+
+impl Default for Nat {
+    fn default() -> Nat {
+        Nat {
+            egress: None,
+            masquerade: true,
+            forward: true,
+        }
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// The iptables commands implementing a server's NAT policy and peer
+/// isolation, as a pair of PostUp and PostDown commands.
+pub async fn iptables(server: &Server) -> (Vec<String>, Vec<String>) {
+    let mut up = vec![];
+    let mut down = vec![];
+    let mut families = vec![(
+        "iptables",
+        "net.ipv4.ip_forward",
+        server.address.trunc().to_string(),
+    )];
+    if let Some(address6) = server.address6 {
+        families.push((
+            "ip6tables",
+            "net.ipv6.conf.all.forwarding",
+            address6.trunc().to_string(),
+        ));
+    }
+
+    let egress = egress(&server.nat).await;
+
+    for (iptables, sysctl, network) in families {
+        if server.isolation {
+            up.push(format!("{} -I FORWARD -i %i -o %i -j DROP", iptables));
+            down.push(format!("{} -D FORWARD -i %i -o %i -j DROP", iptables));
+        }
+        if server.nat.forward {
+            up.push(format!("sysctl -q -w {}=1", sysctl));
+            up.push(format!("{} -A FORWARD -i %i -j ACCEPT", iptables));
+            down.push(format!("{} -D FORWARD -i %i -j ACCEPT", iptables));
+        }
+        if let (true, Some(egress)) = (server.nat.masquerade, &egress) {
+            let rule = format!("POSTROUTING -s {} -o {} -j MASQUERADE", network, egress);
+            up.push(format!("{} -t nat -A {}", iptables, rule));
+            down.push(format!("{} -t nat -D {}", iptables, rule));
+        }
+    }
+
+    (up, down)
+}
+
+/// The uplink to masquerade behind, if masquerading is on at all.
+pub async fn egress(nat: &Nat) -> Option<String> {
+    match &nat.egress {
+        Some(egress) => Some(egress.clone()),
+        None if nat.masquerade => detect_egress().await,
+        None => None,
+    }
+}
+
+/// Finds the interface the default route goes out of.
+pub async fn detect_egress() -> Option<String> {
+    let output = Command::new("ip")
+        .args(["route", "show", "default"])
+        .output()
+        .await
+        .ok()?;
+    let output = String::from_utf8(output.stdout).ok()?;
+
+    let mut fields = output
+        .split_whitespace()
+        .skip_while(|field| *field != "dev");
+    fields.nth(1).map(String::from)
+}
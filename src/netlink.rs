@@ -0,0 +1,293 @@
+use crate::masterkey;
+use crate::nat::Hooks;
+use crate::stats::{self, PeerStats};
+use crate::wghelper::Server;
+use chrono::Utc;
+use futures::TryStreamExt;
+use ipnet::IpNet;
+use rtnetlink::{Handle, LinkUnspec, RouteMessageBuilder};
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
+use std::{io, net::IpAddr};
+use tokio::process::Command;
+use wireguard_control::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
+
+/// The MTU wg-quick picks for an interface on a standard 1500 byte uplink.
+const MTU: u32 = 1420;
+
+/// Whether the kernel speaks WireGuard, so that interfaces can be managed
+/// over netlink rather than with wg-quick. Asking for a device that does not
+/// exist fails with `ENODEV` if the generic netlink family is there and with
+/// `ENOENT` if it is not, and asking for the family loads the module.
+pub fn supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        let probe: InterfaceName = "rest-wg-probe".parse().unwrap();
+        match Device::get(&probe, Backend::Kernel) {
+            Ok(_) => true,
+            Err(err) => err.kind() != io::ErrorKind::NotFound,
+        }
+    })
+}
+
+/// Generates a private key and derives its public key, both base64 encoded.
+pub fn generate_keys() -> (String, String) {
+    let private_key = Key::generate_private();
+    let public_key = private_key.get_public();
+    (private_key.to_base64(), public_key.to_base64())
+}
+
+/// Derives the public key for a base64 encoded private key.
+pub fn pubkey(private_key: &str) -> Result<String, String> {
+    Key::from_base64(private_key.trim())
+        .map(|key| key.get_public().to_base64())
+        .map_err(|_| "Invalid private key".to_string())
+}
+
+/// Creates, configures and brings up an interface the way wg-quick would,
+/// running its hooks around it. If any step fails, the interface is removed
+/// again.
+pub async fn up(server: &Server, hooks: &Hooks) -> Result<(), String> {
+    run_hooks(&hooks.pre_up, &server.name).await?;
+    create(server)?;
+
+    let result = match configure(server, Backend::Kernel) {
+        Ok(()) => setup(server).await,
+        Err(err) => Err(err),
+    };
+    let result = match result {
+        Ok(()) => run_hooks(&hooks.post_up, &server.name).await,
+        Err(err) => Err(err),
+    };
+    if result.is_err() {
+        let _ = delete(&server.name).await;
+    }
+    result
+}
+
+/// Removes an interface, running its hooks around it.
+pub async fn down(server: &Server, hooks: &Hooks) -> Result<(), String> {
+    run_hooks(&hooks.pre_down, &server.name).await?;
+    delete(&server.name).await?;
+    run_hooks(&hooks.post_down, &server.name).await
+}
+
+/// Brings the device in line with the server: sets its key and port, adds
+/// and updates the active peers and removes the ones that are no longer
+/// there. Like `wg syncconf`, it only touches what differs, so unchanged peers
+/// keep their sessions. The device has to exist already.
+pub fn configure(server: &Server, backend: Backend) -> Result<(), String> {
+    let name = interface_name(&server.name)?;
+    let key = |key: &str| Key::from_base64(key).map_err(|_| format!("Invalid key {}", key));
+
+    // Applying an update to a kernel interface creates it if it is missing,
+    // which would bring back an interface that was taken down meanwhile.
+    let device = Device::get(&name, backend)
+        .map_err(|err| format!("{} is not running: {}", server.name, err))?;
+    let mut current: HashMap<Key, HashSet<(IpAddr, u8)>> = HashMap::new();
+    for peer in &device.peers {
+        let allowed_ips = peer
+            .config
+            .allowed_ips
+            .iter()
+            .map(|ip| (ip.address, ip.cidr))
+            .collect();
+        current.insert(peer.config.public_key.clone(), allowed_ips);
+    }
+
+    let mut update = DeviceUpdate::new();
+    let private_key = key(&masterkey::open(&server.prikey)?)?;
+    if device.public_key.as_ref() != Some(&private_key.get_public()) {
+        update = update.set_private_key(private_key);
+    }
+    // Binding the port again drops the endpoints the peers roamed to.
+    if device.listen_port != Some(server.port) {
+        update = update.set_listen_port(server.port);
+    }
+
+    let now = Utc::now();
+    let mut peers = vec![];
+    for peer in server.peers.iter().filter(|peer| peer.active(now)) {
+        let public_key = key(&peer.pubkey)?;
+        let routes = peer.routes();
+        let allowed_ips: HashSet<(IpAddr, u8)> = routes
+            .iter()
+            .map(|route| (route.addr(), route.prefix_len()))
+            .collect();
+        match current.remove(&public_key) {
+            Some(existing) if existing == allowed_ips => continue,
+            // The userspace implementation cannot change a peer in place.
+            Some(_) if backend == Backend::Userspace => {
+                update = update.remove_peer_by_key(&public_key);
+            }
+            _ => {}
+        }
+        let mut builder = PeerConfigBuilder::new(&public_key).replace_allowed_ips();
+        for route in routes {
+            builder = builder.add_allowed_ip(route.addr(), route.prefix_len());
+        }
+        peers.push(builder);
+    }
+
+    // Whatever is left on the device is no longer wanted.
+    for public_key in current.keys() {
+        update = update.remove_peer_by_key(public_key);
+    }
+    update
+        .add_peers(&peers)
+        .apply(&name, backend)
+        .map_err(|err| format!("Failed to configure {}: {}", server.name, err))
+}
+
+/// Creates a kernel interface with nothing configured on it yet.
+fn create(server: &Server) -> Result<(), String> {
+    let name = interface_name(&server.name)?;
+    if Device::get(&name, Backend::Kernel).is_ok() {
+        return Err(format!("{} already exists", server.name));
+    }
+    DeviceUpdate::new()
+        .apply(&name, Backend::Kernel)
+        .map_err(|err| format!("Failed to create {}: {}", server.name, err))
+}
+
+/// The names of the WireGuard interfaces that exist.
+pub fn interfaces() -> Result<HashSet<String>, String> {
+    let names = Device::list(Backend::Kernel).map_err(|err| err.to_string())?;
+    Ok(names
+        .iter()
+        .map(|name| name.as_str_lossy().into_owned())
+        .collect())
+}
+
+/// Reads the peers of an interface, keyed by public key, like `stats::dump`.
+pub fn dump(name: &str, backend: Backend) -> HashMap<String, PeerStats> {
+    let device = match interface_name(name)
+        .and_then(|name| Device::get(&name, backend).map_err(|err| err.to_string()))
+    {
+        Ok(device) => device,
+        Err(_) => return HashMap::new(),
+    };
+
+    let now = stats::now();
+    device
+        .peers
+        .into_iter()
+        .map(|peer| {
+            let latest_handshake = peer
+                .stats
+                .last_handshake_time
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|time| time.as_secs());
+            let stats = PeerStats {
+                endpoint: peer.config.endpoint.map(|endpoint| endpoint.to_string()),
+                latest_handshake,
+                rx_bytes: peer.stats.rx_bytes,
+                tx_bytes: peer.stats.tx_bytes,
+                persistent_keepalive: peer.config.persistent_keepalive_interval,
+                online: stats::is_online(latest_handshake, now),
+            };
+            (peer.config.public_key.to_base64(), stats)
+        })
+        .collect()
+}
+
+/// Adds the addresses, brings the link up and adds routes for the networks
+/// behind the peers.
+pub async fn setup(server: &Server) -> Result<(), String> {
+    let handle = connect()?;
+    let index = link_index(&handle, &server.name).await?;
+
+    for address in server.addresses() {
+        handle
+            .address()
+            .add(index, address.addr(), address.prefix_len())
+            .replace()
+            .execute()
+            .await
+            .map_err(|err| format!("Failed to add address {}: {}", address, err))?;
+    }
+
+    handle
+        .link()
+        .set(LinkUnspec::new_with_index(index).mtu(MTU).up().build())
+        .execute()
+        .await
+        .map_err(|err| format!("Failed to bring up {}: {}", server.name, err))?;
+
+    let now = Utc::now();
+    for peer in server.peers.iter().filter(|peer| peer.active(now)) {
+        for cidr in &peer.allowed_ips {
+            add_route(&handle, index, cidr).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn add_route(handle: &Handle, index: u32, cidr: &IpNet) -> Result<(), String> {
+    let failed = |err: &dyn std::fmt::Display| format!("Failed to add route {}: {}", cidr, err);
+    let route = RouteMessageBuilder::<IpAddr>::new()
+        .destination_prefix(cidr.network(), cidr.prefix_len())
+        .map_err(|err| failed(&err))?
+        .output_interface(index)
+        .build();
+    handle
+        .route()
+        .add(route)
+        .replace()
+        .execute()
+        .await
+        .map_err(|err| failed(&err))
+}
+
+async fn delete(name: &str) -> Result<(), String> {
+    let handle = connect()?;
+    let index = link_index(&handle, name).await?;
+    handle
+        .link()
+        .del(index)
+        .execute()
+        .await
+        .map_err(|err| format!("Failed to delete {}: {}", name, err))
+}
+
+fn connect() -> Result<Handle, String> {
+    let (connection, handle, _) =
+        rtnetlink::new_connection().map_err(|err| format!("Failed to open netlink: {}", err))?;
+    tokio::spawn(connection);
+    Ok(handle)
+}
+
+async fn link_index(handle: &Handle, name: &str) -> Result<u32, String> {
+    let link = handle
+        .link()
+        .get()
+        .match_name(name.to_string())
+        .execute()
+        .try_next()
+        .await
+        .map_err(|err| format!("Failed to find {}: {}", name, err))?;
+    link.map(|link| link.header.index)
+        .ok_or_else(|| format!("{} does not exist", name))
+}
+
+fn interface_name(name: &str) -> Result<InterfaceName, String> {
+    name.parse()
+        .map_err(|_| format!("Invalid interface name {}", name))
+}
+
+/// Runs hooks one after another through bash, as wg-quick does, with `%i`
+/// standing for the interface name.
+pub async fn run_hooks(hooks: &[String], name: &str) -> Result<(), String> {
+    for hook in hooks {
+        let output = Command::new("bash")
+            .args(["-c", &hook.replace("%i", name)])
+            .output()
+            .await
+            .map_err(|err| format!("Failed to execute bash: {}", err))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).into_owned());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,294 @@
+use crate::acl::{AclRule, PortRange, Protocol};
+use crate::nat;
+use crate::wghelper::{Peer, Server};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Write, process::Stdio};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// The nftables table holding every rule rest-wg installs.
+const TABLE: &str = "inet rest_wg";
+
+/// Which tool implements an interface's forwarding and NAT rules.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Firewall {
+    /// iptables commands run by wg-quick as PostUp and PostDown hooks.
+    #[default]
+    Iptables,
+    /// Chains in rest-wg's own nftables table, managed by rest-wg itself.
+    Nftables,
+}
+
+/// Creates or rebuilds the chains of a server in one transaction.
+pub async fn apply(server: &Server) -> Result<(), String> {
+    let egress = nat::egress(&server.nat).await;
+
+    if server.nat.forward {
+        sysctl("net.ipv4.ip_forward").await?;
+        if server.address6.is_some() {
+            sysctl("net.ipv6.conf.all.forwarding").await?;
+        }
+    }
+
+    nft(&ruleset(server, egress.as_deref())).await
+}
+
+/// Removes the chains of a server, whether they exist or not.
+pub async fn remove(server: &Server) -> Result<(), String> {
+    let mut script = format!("add table {}\n", TABLE);
+    for chain in chains(server) {
+        // Adding the chain first makes deleting it succeed even if it is gone.
+        writeln!(&mut script, "add chain {} {}", TABLE, chain).unwrap();
+        writeln!(&mut script, "flush chain {} {}", TABLE, chain).unwrap();
+        writeln!(&mut script, "delete chain {} {}", TABLE, chain).unwrap();
+    }
+    nft(&script).await
+}
+
+/// The nft script that installs a server's chains from scratch.
+pub fn ruleset(server: &Server, egress: Option<&str>) -> String {
+    let [forward, postrouting] = chains(server);
+    let iface = &server.name;
+    let mut script = String::new();
+
+    writeln!(&mut script, "add table {}", TABLE).unwrap();
+    writeln!(
+        &mut script,
+        "add chain {} {} {{ type filter hook forward priority 0; policy accept; }}",
+        TABLE, forward
+    )
+    .unwrap();
+    writeln!(&mut script, "flush chain {} {}", TABLE, forward).unwrap();
+    writeln!(
+        &mut script,
+        "add chain {} {} {{ type nat hook postrouting priority 100; }}",
+        TABLE, postrouting
+    )
+    .unwrap();
+    writeln!(&mut script, "flush chain {} {}", TABLE, postrouting).unwrap();
+
+    if server.isolation {
+        writeln!(
+            &mut script,
+            "add rule {} {} iifname \"{}\" oifname \"{}\" drop",
+            TABLE, forward, iface, iface
+        )
+        .unwrap();
+    }
+
+    // Peers with an ACL may only start connections to what it lists, so
+    // their rules have to come before the blanket accept below.
+    let restricted: Vec<&Peer> = server
+        .peers
+        .iter()
+        .filter(|peer| peer.enabled && !peer.acl.is_empty())
+        .collect();
+    if !restricted.is_empty() {
+        writeln!(
+            &mut script,
+            "add rule {} {} iifname \"{}\" ct state established,related accept",
+            TABLE, forward, iface
+        )
+        .unwrap();
+    }
+    for peer in restricted {
+        for rule in &peer.acl {
+            if let Some(matches) = acl_match(peer, rule) {
+                writeln!(
+                    &mut script,
+                    "add rule {} {} iifname \"{}\" {} accept",
+                    TABLE, forward, iface, matches
+                )
+                .unwrap();
+            }
+        }
+        // Everything the peer routes through the tunnel is held to its ACL,
+        // not only its tunnel addresses.
+        for family in ["ip", "ip6"] {
+            if let Some(sources) = sources(peer, family) {
+                writeln!(
+                    &mut script,
+                    "add rule {} {} iifname \"{}\" {} saddr {} drop",
+                    TABLE, forward, iface, family, sources
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    if server.nat.forward {
+        writeln!(
+            &mut script,
+            "add rule {} {} iifname \"{}\" accept",
+            TABLE, forward, iface
+        )
+        .unwrap();
+    }
+
+    if let (true, Some(egress)) = (server.nat.masquerade, egress) {
+        for network in server.addresses().iter().map(IpNet::trunc) {
+            writeln!(
+                &mut script,
+                "add rule {} {} {} saddr {} oifname \"{}\" masquerade",
+                TABLE,
+                postrouting,
+                family(&network),
+                network,
+                egress
+            )
+            .unwrap();
+        }
+    }
+
+    script
+}
+
+/// The match expression for traffic from a peer that an ACL rule allows, or
+/// `None` if the peer routes nothing in the rule's address family.
+fn acl_match(peer: &Peer, rule: &AclRule) -> Option<String> {
+    let family = family(&rule.destination);
+    let mut matches = format!(
+        "{} saddr {} {} daddr {}",
+        family,
+        sources(peer, family)?,
+        family,
+        rule.destination
+    );
+
+    let protocol = match (rule.protocol, family) {
+        (Protocol::Any, _) => None,
+        (Protocol::Tcp, _) => Some("tcp"),
+        (Protocol::Udp, _) => Some("udp"),
+        (Protocol::Icmp, "ip") => Some("icmp"),
+        (Protocol::Icmp, _) => Some("icmpv6"),
+    };
+    if let Some(protocol) = protocol {
+        write!(&mut matches, " meta l4proto {}", protocol).unwrap();
+    }
+    if !rule.ports.is_empty() {
+        let ports: Vec<String> = rule.ports.iter().map(PortRange::to_string).collect();
+        write!(
+            &mut matches,
+            " {} dport {{ {} }}",
+            protocol.unwrap(),
+            ports.join(", ")
+        )
+        .unwrap();
+    }
+    Some(matches)
+}
+
+/// The set of everything a peer routes in an address family, or `None` if
+/// it routes nothing there.
+fn sources(peer: &Peer, family_name: &str) -> Option<String> {
+    let routes: Vec<String> = peer
+        .routes()
+        .iter()
+        .filter(|route| family(route) == family_name)
+        .map(|route| route.trunc().to_string())
+        .collect();
+    if routes.is_empty() {
+        return None;
+    }
+    Some(format!("{{ {} }}", routes.join(", ")))
+}
+
+/// The nftables name of a network's address family.
+fn family(network: &IpNet) -> &'static str {
+    match network {
+        IpNet::V4(_) => "ip",
+        IpNet::V6(_) => "ip6",
+    }
+}
+
+/// The names of a server's forward and postrouting chains.
+fn chains(server: &Server) -> [String; 2] {
+    let name: String = server
+        .name
+        .chars()
+        .map(|ch| if ch.is_ascii_alphanumeric() { ch } else { '_' })
+        .collect();
+    [format!("{}_forward", name), format!("{}_postrouting", name)]
+}
+
+async fn nft(script: &str) -> Result<(), String> {
+    let mut command = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Failed to execute nft: {}", err))?;
+
+    command
+        .stdin
+        .as_mut()
+        .ok_or("Failed to get stdin for nft")?
+        .write_all(script.as_bytes())
+        .await
+        .map_err(|err| format!("Failed to write to nft: {}", err))?;
+
+    let output = command
+        .wait_with_output()
+        .await
+        .map_err(|err| format!("Failed to get output from nft: {}", err))?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+async fn sysctl(key: &str) -> Result<(), String> {
+    let output = Command::new("sysctl")
+        .args(["-q", "-w", &format!("{}=1", key)])
+        .output()
+        .await
+        .map_err(|err| format!("Failed to execute sysctl: {}", err))?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn acls_cover_everything_a_peer_routes() {
+        let server = testutil::server(
+            "wg0",
+            r#"
+            firewall = "nftables"
+
+            [[peers]]
+            name = "office"
+            address = "10.8.0.2/24"
+            allowed_ips = ["192.168.55.0/24"]
+            prikey = ""
+            pubkey = ""
+            enabled = true
+
+            [[peers.acl]]
+            destination = "10.1.0.0/16"
+            protocol = "tcp"
+            ports = ["443"]
+            "#,
+        );
+        let [forward, _] = chains(&server);
+        let ruleset = ruleset(&server, None);
+
+        let sources = "{ 10.8.0.2/32, 192.168.55.0/24 }";
+        assert!(ruleset.contains(&format!(
+            "add rule {} {} iifname \"wg0\" ip saddr {} ip daddr 10.1.0.0/16 meta l4proto tcp tcp dport {{ 443 }} accept",
+            TABLE, forward, sources
+        )));
+        assert!(ruleset.contains(&format!(
+            "add rule {} {} iifname \"wg0\" ip saddr {} drop",
+            TABLE, forward, sources
+        )));
+    }
+}
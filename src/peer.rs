@@ -1,118 +1,403 @@
 use crate::{
-    state::SharedState,
-    wghelper::{Peer, Wg},
+    backend,
+    error::Error,
+    extract::{Json, Path},
+    ipam::{self, Pool},
+    quota::{self, QuotaStatus},
+    schedule::AccessWindow,
+    state::{SharedState, SharedTraffic},
+    stats::{self, PeerStats},
+    storage::Change,
+    tc::RateLimit,
+    wghelper::{Peer, Server, Wg},
 };
-use axum::{extract::Path, http::StatusCode, Extension, Json};
-use serde::Deserialize;
+use axum::{http::StatusCode, Extension};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize)]
 pub struct CreatePeer {
     name: String,
+    address: Option<String>,
+    address6: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    schedule: Vec<AccessWindow>,
+    rate_limit: Option<RateLimit>,
 }
 
-/*
 #[derive(Debug, Deserialize)]
 pub struct UpdatePeerConf {
     name: Option<String>,
-    publickey: Option<String>,
-    privatekey: Option<String>,
     address: Option<String>,
+    address6: Option<String>,
     enabled: Option<bool>,
-    allowedip: Option<Vec<String>>,
+    allowed_ips: Option<Vec<IpNet>>,
+    /// An empty string clears the expiry time.
+    expires_at: Option<String>,
+    schedule: Option<Vec<AccessWindow>>,
+    /// A limit without any rates removes it.
+    rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    regenerate_keys: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerStatus {
+    #[serde(flatten)]
+    peer: Peer,
+    online: bool,
+    /// Whether the peer is on the interface right now.
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota_status: Option<QuotaStatus>,
 }
-*/
 
 pub async fn get_peers(
-    Path(server_id): Path<usize>,
+    Path(iface): Path<String>,
     Extension(state): Extension<SharedState>,
-) -> Result<Json<Vec<Peer>>, StatusCode> {
+    Extension(traffic): Extension<SharedTraffic>,
+) -> Result<Json<Vec<PeerStatus>>, Error> {
     let state = state.read().await;
-    if let Some(server) = state.servers.get(server_id) {
-        return Ok(Json(server.peers.clone()));
-    }
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    let server_id = state.server_id(&iface)?;
+    let server = &state.servers[server_id];
+    let stats = stats::dump(&server.name).await;
+    let traffic = traffic.lock().await;
+    let now = Utc::now();
+    let peers = server
+        .peers
+        .iter()
+        .map(|peer| PeerStatus {
+            peer: peer.clone(),
+            online: stats.get(&peer.pubkey).is_some_and(|stats| stats.online),
+            active: peer.active(now),
+            quota_status: peer
+                .quota
+                .as_ref()
+                .map(|quota| quota.status(&traffic, peer, now)),
+        })
+        .collect();
+    Ok(Json(peers))
 }
 
 pub async fn get_peer(
-    Path((server_id, peer_id)): Path<(usize, usize)>,
+    Path((iface, peer)): Path<(String, String)>,
     Extension(state): Extension<SharedState>,
-) -> Result<Json<Peer>, StatusCode> {
+    Extension(traffic): Extension<SharedTraffic>,
+) -> Result<Json<PeerStatus>, Error> {
     let state = state.read().await;
-    if let Some(server) = state.servers.get(server_id) {
-        if let Some(peer) = server.peers.get(peer_id) {
-            return Ok(axum::Json(peer.clone()));
-        }
-    }
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    let server_id = state.server_id(&iface)?;
+    let peer_id = state.peer_id(server_id, &peer)?;
+    let server = &state.servers[server_id];
+    let peer = &server.peers[peer_id];
+    let stats = stats::dump(&server.name).await;
+    let traffic = traffic.lock().await;
+    let now = Utc::now();
+    Ok(Json(PeerStatus {
+        peer: peer.clone(),
+        online: stats.get(&peer.pubkey).is_some_and(|stats| stats.online),
+        active: peer.active(now),
+        quota_status: peer
+            .quota
+            .as_ref()
+            .map(|quota| quota.status(&traffic, peer, now)),
+    }))
+}
+
+pub async fn get_peer_stats(
+    Path((iface, peer)): Path<(String, String)>,
+    Extension(state): Extension<SharedState>,
+) -> Result<Json<PeerStats>, Error> {
+    let state = state.read().await;
+    let server_id = state.server_id(&iface)?;
+    let peer_id = state.peer_id(server_id, &peer)?;
+    let server = &state.servers[server_id];
+    let mut stats = stats::dump(&server.name).await;
+    let stats = stats
+        .remove(&server.peers[peer_id].pubkey)
+        .unwrap_or_default();
+    Ok(Json(stats))
 }
 
 pub async fn create_peer(
     Json(create_peer): Json<CreatePeer>,
-    Path(server_id): Path<usize>,
+    Path(iface): Path<String>,
     Extension(state): Extension<SharedState>,
-) -> Result<StatusCode, StatusCode> {
+) -> Result<StatusCode, Error> {
     let mut state = state.write().await;
-    if state.servers.get(server_id).is_some() {
-        state.create_peer(&create_peer.name, server_id).await;
-        Wg::dump_state(&state).await;
-        return Ok(StatusCode::OK);
+    let server_id = state.server_id(&iface)?;
+    if create_peer.name.is_empty() {
+        return Err(Error::bad_request("Invalid peer name"));
+    }
+    if state.peer_index(server_id, &create_peer.name).is_some() {
+        return Err(Error::conflict(format!(
+            "{} already exists",
+            create_peer.name
+        )));
+    }
+    if create_peer
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Err(Error::bad_request("The expiry time has passed"));
+    }
+    if !create_peer.schedule.iter().all(AccessWindow::is_valid) {
+        return Err(Error::bad_request("Access windows must not be empty"));
+    }
+    if !create_peer.rate_limit.is_none_or(|limit| limit.is_valid()) {
+        return Err(Error::bad_request("Rate limits must not be zero"));
     }
+    let address = match &create_peer.address {
+        Some(address) => Some(ipam::parse_address(address).ok_or_else(invalid_address)?),
+        None => None,
+    };
+    let address6 = match &create_peer.address6 {
+        Some(address6) => Some(ipam::parse_address(address6).ok_or_else(invalid_address)?),
+        None => None,
+    };
+    let peer = state
+        .create_peer(&create_peer.name, server_id, address, address6)
+        .map_err(Error::BadRequest)?;
+    peer.expires_at = create_peer.expires_at;
+    peer.schedule = create_peer.schedule;
+    peer.rate_limit = create_peer.rate_limit.filter(|limit| !limit.is_empty());
+    let change = Change::Peer(peer.id);
+    Wg::dump_state(&state, &[change]).await?;
+    Ok(StatusCode::OK)
+}
 
-    Ok(StatusCode::INTERNAL_SERVER_ERROR)
+fn invalid_address() -> Error {
+    Error::bad_request("Invalid address")
+}
+
+/// Makes sure the extra networks of a peer stay clear of the interface's own
+/// networks and of everything routed to its other peers. WireGuard routes
+/// each address to one peer only, so an overlap would take it away from the
+/// peer that has it now.
+fn check_allowed_ips(server: &Server, peer_id: usize, allowed_ips: &[IpNet]) -> Result<(), Error> {
+    for cidr in allowed_ips {
+        let network = server
+            .addresses()
+            .into_iter()
+            .find(|network| ipam::overlaps(cidr, network));
+        if let Some(network) = network {
+            return Err(Error::bad_request(format!(
+                "{} overlaps the network {} of {}",
+                cidr,
+                network.trunc(),
+                server.name
+            )));
+        }
+        let peer = server
+            .peers
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != peer_id)
+            .find(|(_, peer)| {
+                peer.routes()
+                    .iter()
+                    .any(|route| ipam::overlaps(cidr, route))
+            });
+        if let Some((_, peer)) = peer {
+            return Err(Error::conflict(format!(
+                "{} overlaps what is routed to {}",
+                cidr, peer.name
+            )));
+        }
+    }
+    Ok(())
 }
 
-/*
 pub async fn update_peer(
-    Json(updated_peer): Json<UpdatePeerConf>,
+    Path((iface, peer)): Path<(String, String)>,
     Extension(state): Extension<SharedState>,
-    Path((server_id, peer_id)): Path<(usize, usize)>,
-) -> Result<StatusCode, StatusCode> {
-    todo!()
+    Extension(traffic): Extension<SharedTraffic>,
+    Json(update): Json<UpdatePeerConf>,
+) -> Result<StatusCode, Error> {
     let mut state = state.write().await;
-    if state.interfaces.len() > iface_id && state.interfaces[iface_id].peer.len() > peer_id {
-        // name
-        if let Some(name) = updated_peer.name {
-            state.interfaces[iface_id].peer[peer_id].name = name;
-        }
-        // publickey
-        if let Some(publickey) = updated_peer.publickey {
-            state.interfaces[iface_id].peer[peer_id].publickey = publickey;
+    let server_id = state.server_id(&iface)?;
+    let peer_id = state.peer_id(server_id, &peer)?;
+
+    if update.enabled == Some(true) {
+        let traffic = traffic.lock().await;
+        quota::check_resume(&state.servers[server_id].peers[peer_id], &traffic)?;
+    }
+
+    if let Some(name) = &update.name {
+        if name.is_empty() {
+            return Err(Error::bad_request("Invalid peer name"));
         }
-        // privatekey
-        if let Some(privatekey) = updated_peer.privatekey {
-            state.interfaces[iface_id].peer[peer_id].privatekey = privatekey;
+        let taken = state
+            .peer_index(server_id, name)
+            .is_some_and(|other| other != peer_id);
+        if taken {
+            return Err(Error::conflict(format!("{} already exists", name)));
         }
-        // address
-        if let Some(address) = updated_peer.address {
-            state.interfaces[iface_id].peer[peer_id].address = address;
+    }
+
+    let address =
+        match &update.address {
+            Some(address) => {
+                let address = ipam::parse_address(address).ok_or_else(invalid_address)?;
+                let mut pool = Pool::v4(&state.servers[server_id], Some(peer_id));
+                Some(pool.reserve(address).ok_or_else(|| {
+                    Error::bad_request(format!("{} is not a free address", address))
+                })?)
+            }
+            None => None,
+        };
+
+    let address6 = match &update.address6 {
+        Some(address6) => {
+            let address6 = ipam::parse_address(address6).ok_or_else(invalid_address)?;
+            let pool = Pool::v6(&state.servers[server_id], Some(peer_id));
+            Wg::take_address(pool, Some(address6)).map_err(Error::BadRequest)?
         }
-        // enabled
-        if let Some(enabled) = updated_peer.enabled {
-            state.interfaces[iface_id].peer[peer_id].enabled = enabled;
+        None => None,
+    };
+
+    let expires_at = match update.expires_at.as_deref() {
+        Some("") => Some(None),
+        Some(expires_at) => Some(Some(
+            expires_at
+                .parse::<DateTime<Utc>>()
+                .map_err(|_| Error::bad_request("Invalid expiry time"))?,
+        )),
+        None => None,
+    };
+
+    if let Some(schedule) = &update.schedule {
+        if !schedule.iter().all(AccessWindow::is_valid) {
+            return Err(Error::bad_request("Access windows must not be empty"));
         }
-        // allowedip
-        if let Some(allowedip) = updated_peer.allowedip {
-            state.interfaces[iface_id].peer[peer_id].allowedip = allowedip;
+    }
+    if update.rate_limit.is_some_and(|limit| !limit.is_valid()) {
+        return Err(Error::bad_request("Rate limits must not be zero"));
+    }
+    if let Some(allowed_ips) = &update.allowed_ips {
+        check_allowed_ips(&state.servers[server_id], peer_id, allowed_ips)?;
+    }
+
+    let keys = if update.regenerate_keys {
+        Some(Wg::get_keys())
+    } else {
+        None
+    };
+
+    let server_name = state.servers[server_id].name.clone();
+    let peer = &mut state.servers[server_id].peers[peer_id];
+    if let Some(name) = update.name {
+        peer.name = name;
+    }
+    if let Some(address) = address {
+        peer.address = address;
+    }
+    if address6.is_some() {
+        peer.address6 = address6;
+    }
+    if let Some(enabled) = update.enabled {
+        // A peer suspended by hand stays suspended once its quota frees up.
+        peer.enabled = enabled;
+        peer.over_quota = false;
+    }
+    let mut stale_routes = vec![];
+    if let Some(allowed_ips) = update.allowed_ips {
+        stale_routes = peer
+            .allowed_ips
+            .iter()
+            .filter(|cidr| !allowed_ips.contains(cidr))
+            .cloned()
+            .collect();
+        peer.allowed_ips = allowed_ips;
+    }
+    if let Some(expires_at) = expires_at {
+        peer.expires_at = expires_at;
+    }
+    if let Some(schedule) = update.schedule {
+        peer.schedule = schedule;
+    }
+    if let Some(rate_limit) = update.rate_limit {
+        peer.rate_limit = Some(rate_limit).filter(|limit| !limit.is_empty());
+    }
+    if let Some((prikey, pubkey)) = keys {
+        peer.prikey = prikey;
+        peer.pubkey = pubkey;
+    }
+    let change = Change::Peer(peer.id);
+
+    Wg::dump_state(&state, &[change]).await?;
+
+    // Syncing only changes what differs, so other peers keep their sessions.
+    if Wg::server_status().await?.contains(&server_name) {
+        state.hot_reload(server_id).await?;
+        for cidr in &stale_routes {
+            backend::get().remove_route(&server_name, cidr).await;
         }
+    }
+    Ok(StatusCode::OK)
+}
 
-        wghelper::write_config(&state).await;
-        return Ok(StatusCode::OK);
+pub async fn suspend_peer(
+    Path((iface, peer)): Path<(String, String)>,
+    Extension(state): Extension<SharedState>,
+    Extension(traffic): Extension<SharedTraffic>,
+) -> Result<StatusCode, Error> {
+    set_enabled(&state, &traffic, &iface, &peer, false).await
+}
+
+pub async fn resume_peer(
+    Path((iface, peer)): Path<(String, String)>,
+    Extension(state): Extension<SharedState>,
+    Extension(traffic): Extension<SharedTraffic>,
+) -> Result<StatusCode, Error> {
+    set_enabled(&state, &traffic, &iface, &peer, true).await
+}
+
+async fn set_enabled(
+    state: &SharedState,
+    traffic: &SharedTraffic,
+    iface: &str,
+    peer: &str,
+    enabled: bool,
+) -> Result<StatusCode, Error> {
+    let mut state = state.write().await;
+    let server_id = state.server_id(iface)?;
+    let peer_id = state.peer_id(server_id, peer)?;
+    if enabled {
+        let traffic = traffic.lock().await;
+        quota::check_resume(&state.servers[server_id].peers[peer_id], &traffic)?;
+    }
+    let peer = &mut state.servers[server_id].peers[peer_id];
+    // Without the mark, enforcing the quota leaves the peer as it is set here.
+    peer.enabled = enabled;
+    peer.over_quota = false;
+    let change = Change::Peer(peer.id);
+    Wg::dump_state(&state, &[change]).await?;
+    if Wg::server_status()
+        .await?
+        .contains(&state.servers[server_id].name)
+    {
+        state.hot_reload(server_id).await?;
     }
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    Ok(StatusCode::OK)
 }
-*/
 
 pub async fn delete_peer(
-    Path((server_id, peer_id)): Path<(usize, usize)>,
+    Path((iface, peer)): Path<(String, String)>,
     Extension(state): Extension<SharedState>,
-) -> Result<StatusCode, StatusCode> {
+) -> Result<StatusCode, Error> {
     let mut state = state.write().await;
-    if let Some(server) = state.servers.get_mut(server_id) {
-        if server.peers.get(peer_id).is_some() {
-            server.peers.remove(peer_id);
-            Wg::dump_state(&state).await;
-            return Ok(StatusCode::OK);
+    let server_id = state.server_id(&iface)?;
+    let peer_id = state.peer_id(server_id, &peer)?;
+    let peer = state.servers[server_id].peers.remove(peer_id);
+    Wg::dump_state(&state, &[Change::Peer(peer.id)]).await?;
+    let server_name = &state.servers[server_id].name;
+    if Wg::server_status().await?.contains(server_name) {
+        state.hot_reload(server_id).await?;
+        for cidr in &peer.allowed_ips {
+            backend::get().remove_route(server_name, cidr).await;
         }
     }
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    Ok(StatusCode::OK)
 }
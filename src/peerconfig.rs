@@ -1,34 +1,46 @@
 use axum::{
-    extract::Path,
-    http::{header, HeaderMap, StatusCode},
+    http::{header, HeaderMap},
     Extension,
 };
 
+use crate::error::Error;
+use crate::extract::Path;
 use crate::state::SharedState;
 
 pub async fn get_config(
-    Path((server_id, peer_id)): Path<(usize, usize)>,
+    Path((iface, peer)): Path<(String, String)>,
     Extension(state): Extension<SharedState>,
-) -> Result<(HeaderMap, String), StatusCode> {
+) -> Result<(HeaderMap, String), Error> {
     let state = state.read().await;
+    let server_id = state.server_id(&iface)?;
+    let peer_id = state.peer_id(server_id, &peer)?;
 
-    if let Some(server) = state.servers.get(server_id) {
-        if let Some(peer) = server.peers.get(peer_id) {
-            let peer_config = state.peer_config(server_id, peer_id);
-            let mut headers = HeaderMap::new();
-            headers.insert(
-                header::ACCESS_CONTROL_EXPOSE_HEADERS,
-                "Content-Disposition".parse().unwrap(),
-            );
-            headers.insert(
-                header::CONTENT_DISPOSITION,
-                format!("attachment; filename=\"{}.conf\"", peer.name)
-                    .parse()
-                    .unwrap(),
-            );
-            headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
-            return Ok((headers, peer_config));
-        }
-    }
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    let peer = &state.servers[server_id].peers[peer_id];
+    let peer_config = state
+        .peer_config(server_id, peer_id)
+        .map_err(Error::Internal)?;
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        "Content-Disposition".parse().unwrap(),
+    );
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}.conf\"", file_name(&peer.name))
+            .parse()
+            .unwrap(),
+    );
+    headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+    Ok((headers, peer_config))
+}
+
+/// The peer's name with everything that does not belong in a header or a
+/// file name replaced.
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|ch| match ch {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => ch,
+            _ => '_',
+        })
+        .collect()
 }
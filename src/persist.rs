@@ -0,0 +1,104 @@
+use std::fs::{self, File, OpenOptions, Permissions, TryLockError};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+/// How many earlier versions of a file to keep next to it, as `<path>.1`
+/// (the newest) up to `<path>.5`.
+pub const BACKUPS: usize = 5;
+
+/// Takes an exclusive lock on `<path>.lock` for as long as the returned file
+/// is kept open, failing if another process holds it already.
+pub fn lock(path: &str) -> Result<File, String> {
+    let lock_path = format!("{}.lock", path);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|err| format!("Failed to open {}: {}", lock_path, err))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(format!(
+            "{} is locked, is another rest-wg running here?",
+            lock_path
+        )),
+        Err(TryLockError::Error(err)) => Err(format!("Failed to lock {}: {}", lock_path, err)),
+    }
+}
+
+/// Replaces a file so that it holds either the old or the new contents even
+/// if the process or the host dies halfway. The new contents go to a
+/// temporary file that is synced and renamed over the old one, which is kept
+/// as the newest backup. Only the owner can read the file and its backups,
+/// as they hold keys.
+pub fn write(path: &str, contents: &[u8]) -> io::Result<()> {
+    let temp = format!("{}.tmp", path);
+    let mut file = create_private(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    if Path::new(path).exists() {
+        rotate(path)?;
+    }
+    fs::rename(&temp, path)?;
+    sync_dir(path)
+}
+
+/// The backups of a file, newest first.
+pub fn backups(path: &str) -> impl Iterator<Item = String> + '_ {
+    (1..=BACKUPS).map(move |generation| backup(path, generation))
+}
+
+/// Deletes the backups of a file, such as when they hold secrets that have
+/// since been changed.
+pub fn remove_backups(path: &str) -> io::Result<()> {
+    for backup in backups(path) {
+        match fs::remove_file(&backup) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Shifts the backups along by one, dropping the oldest, and copies the
+/// current file into the newest slot.
+fn rotate(path: &str) -> io::Result<()> {
+    for generation in (1..BACKUPS).rev() {
+        let from = backup(path, generation);
+        if Path::new(&from).exists() {
+            fs::rename(&from, backup(path, generation + 1))?;
+        }
+    }
+    let mut copy = create_private(&backup(path, 1))?;
+    io::copy(&mut File::open(path)?, &mut copy)?;
+    copy.sync_all()
+}
+
+/// Creates or truncates a file that only its owner can read and write.
+fn create_private(path: &str) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode only applies to a new file, not to one left over from before.
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+fn backup(path: &str, generation: usize) -> String {
+    format!("{}.{}", path, generation)
+}
+
+/// Syncs the directory holding a file, so that a rename in it is durable.
+fn sync_dir(path: &str) -> io::Result<()> {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
@@ -0,0 +1,286 @@
+use crate::error::Error;
+use crate::extract::{Json, Path};
+use crate::state::{SharedState, SharedTraffic};
+use crate::storage::Change;
+use crate::traffic::{Period, Traffic};
+use crate::wghelper::{Peer, Wg};
+use axum::{http::StatusCode, Extension};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The most days a rolling quota may span, as daily totals are not kept for
+/// much longer.
+const MAX_ROLLING_DAYS: u32 = 366;
+
+/// How many bytes a peer may transfer, counting both directions, before it is
+/// suspended until enough of its traffic falls out of the quota's window.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    pub bytes: u64,
+    /// Counts the last this many days, today included, instead of the
+    /// calendar month.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub rolling_days: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub overrides: Vec<Override>,
+}
+
+/// Extra bytes granted on top of a quota. An override lasts as long as the
+/// window it was made in, but stays on record after that.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Override {
+    pub by: String,
+    pub at: DateTime<Utc>,
+    pub bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaStatus {
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub exceeded: bool,
+}
+
+impl Quota {
+    pub fn is_valid(&self) -> bool {
+        self.rolling_days
+            .is_none_or(|days| (1..=MAX_ROLLING_DAYS).contains(&days))
+    }
+
+    /// A point in the first bucket of the current window.
+    fn start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self.rolling_days {
+            Some(days) => now - Duration::days(i64::from(days) - 1),
+            None => now,
+        }
+    }
+
+    /// Whether an override was made in the current window.
+    fn applies(&self, over: &Override, now: DateTime<Utc>) -> bool {
+        match self.rolling_days {
+            Some(_) => Period::Day.bucket(over.at) >= Period::Day.bucket(self.start(now)),
+            None => Period::Month.bucket(over.at) == Period::Month.bucket(now),
+        }
+    }
+
+    pub fn status(&self, traffic: &Traffic, peer: &Peer, now: DateTime<Utc>) -> QuotaStatus {
+        let period = match self.rolling_days {
+            Some(_) => Period::Day,
+            None => Period::Month,
+        };
+        let usage = traffic.since(peer.id, period, self.start(now));
+        let used = usage.rx_bytes + usage.tx_bytes;
+        let limit = self.bytes
+            + self
+                .overrides
+                .iter()
+                .filter(|over| self.applies(over, now))
+                .map(|over| over.bytes)
+                .sum::<u64>();
+        QuotaStatus {
+            limit,
+            used,
+            remaining: limit.saturating_sub(used),
+            exceeded: used >= limit,
+        }
+    }
+}
+
+/// Refuses to resume a peer by hand while it is over its quota, as the next
+/// sample would only suspend it again. An override lifts the quota instead,
+/// and keeps a record of who did it.
+pub fn check_resume(peer: &Peer, traffic: &Traffic) -> Result<(), Error> {
+    let exceeded = peer
+        .quota
+        .as_ref()
+        .is_some_and(|quota| quota.status(traffic, peer, Utc::now()).exceeded);
+    if exceeded {
+        return Err(Error::conflict(format!(
+            "{} is over its quota, add an override to resume it",
+            peer.name
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetQuota {
+    bytes: u64,
+    rolling_days: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateOverride {
+    by: String,
+    bytes: u64,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuotaEntry {
+    #[serde(flatten)]
+    quota: Quota,
+    status: QuotaStatus,
+}
+
+/// Suspends the peers that used up their quota and resumes the ones it
+/// suspended once their usage is back under it, reloading the interfaces
+/// that changed.
+pub async fn enforce(state: &SharedState, traffic: &SharedTraffic) {
+    let mut state = state.write().await;
+    let traffic = traffic.lock().await;
+    let now = Utc::now();
+
+    let mut changed = vec![];
+    let mut changes = vec![];
+    for (server_id, server) in state.servers.iter_mut().enumerate() {
+        let mut reload = false;
+        for peer in &mut server.peers {
+            let exceeded = match &peer.quota {
+                Some(quota) => quota.status(&traffic, peer, now).exceeded,
+                None => false,
+            };
+            if exceeded && peer.enabled {
+                peer.enabled = false;
+                peer.over_quota = true;
+            } else if !exceeded && peer.over_quota {
+                peer.enabled = true;
+                peer.over_quota = false;
+            } else {
+                continue;
+            }
+            changes.push(Change::Peer(peer.id));
+            reload = true;
+        }
+        if reload {
+            changed.push(server_id);
+        }
+    }
+    if changed.is_empty() {
+        return;
+    }
+
+    if let Err(err) = Wg::dump_state(&state, &changes).await {
+        eprintln!("{}", err);
+    }
+    let running = match Wg::server_status().await {
+        Ok(running) => running,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+    for server_id in changed {
+        if running.contains(&state.servers[server_id].name) {
+            if let Err(err) = state.hot_reload(server_id).await {
+                eprintln!("{}", err);
+            }
+        }
+    }
+}
+
+pub async fn get_quota(
+    Path((iface, peer)): Path<(String, String)>,
+    Extension(state): Extension<SharedState>,
+    Extension(traffic): Extension<SharedTraffic>,
+) -> Result<Json<QuotaEntry>, Error> {
+    let state = state.read().await;
+    let server_id = state.server_id(&iface)?;
+    let peer_id = state.peer_id(server_id, &peer)?;
+    let peer = &state.servers[server_id].peers[peer_id];
+    let quota = peer.quota.clone().ok_or_else(no_quota)?;
+
+    let traffic = traffic.lock().await;
+    let status = quota.status(&traffic, peer, Utc::now());
+    Ok(Json(QuotaEntry { quota, status }))
+}
+
+pub async fn set_quota(
+    Path((iface, peer)): Path<(String, String)>,
+    Extension(state): Extension<SharedState>,
+    Extension(traffic): Extension<SharedTraffic>,
+    Json(update): Json<SetQuota>,
+) -> Result<StatusCode, Error> {
+    change_quota(&state, &traffic, &iface, &peer, |quota| {
+        let overrides = quota.as_ref().map(|quota| quota.overrides.clone());
+        let new = Quota {
+            bytes: update.bytes,
+            rolling_days: update.rolling_days,
+            overrides: overrides.unwrap_or_default(),
+        };
+        if !new.is_valid() {
+            return Err(Error::bad_request(format!(
+                "A rolling quota spans 1 to {} days",
+                MAX_ROLLING_DAYS
+            )));
+        }
+        *quota = Some(new);
+        Ok(())
+    })
+    .await
+}
+
+pub async fn delete_quota(
+    Path((iface, peer)): Path<(String, String)>,
+    Extension(state): Extension<SharedState>,
+    Extension(traffic): Extension<SharedTraffic>,
+) -> Result<StatusCode, Error> {
+    change_quota(&state, &traffic, &iface, &peer, |quota| {
+        quota.take().map(|_| ()).ok_or_else(no_quota)
+    })
+    .await
+}
+
+pub async fn create_override(
+    Path((iface, peer)): Path<(String, String)>,
+    Extension(state): Extension<SharedState>,
+    Extension(traffic): Extension<SharedTraffic>,
+    Json(create): Json<CreateOverride>,
+) -> Result<StatusCode, Error> {
+    if create.by.trim().is_empty() {
+        return Err(Error::bad_request("An override needs to say who made it"));
+    }
+    change_quota(&state, &traffic, &iface, &peer, |quota| {
+        let quota = quota.as_mut().ok_or_else(no_quota)?;
+        quota.overrides.push(Override {
+            by: create.by,
+            at: Utc::now(),
+            bytes: create.bytes,
+            reason: create.reason,
+        });
+        Ok(())
+    })
+    .await
+}
+
+fn no_quota() -> Error {
+    Error::NotFound("The peer has no quota".into())
+}
+
+/// Applies a change to a peer's quota, saves it and enforces the result
+/// right away.
+async fn change_quota<F>(
+    state: &SharedState,
+    traffic: &SharedTraffic,
+    iface: &str,
+    peer: &str,
+    change: F,
+) -> Result<StatusCode, Error>
+where
+    F: FnOnce(&mut Option<Quota>) -> Result<(), Error>,
+{
+    {
+        let mut state = state.write().await;
+        let server_id = state.server_id(iface)?;
+        let peer_id = state.peer_id(server_id, peer)?;
+        let peer = &mut state.servers[server_id].peers[peer_id];
+        change(&mut peer.quota)?;
+        let change = Change::Peer(peer.id);
+        Wg::dump_state(&state, &[change]).await?;
+    }
+    enforce(state, traffic).await;
+    Ok(StatusCode::OK)
+}
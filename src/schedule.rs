@@ -0,0 +1,199 @@
+use crate::error::Error;
+use crate::extract::{Json, Path};
+use crate::state::SharedState;
+use crate::storage::Change;
+use crate::wghelper::{Peer, Wg};
+use axum::Extension;
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Timelike, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// A recurring stretch of time, in UTC, during which a peer may connect.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessWindow {
+    /// The days the window opens on, or every day if empty.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    /// Where the window closes. A window that ends before it starts runs
+    /// past midnight into the next day.
+    pub end: NaiveTime,
+}
+
+impl AccessWindow {
+    pub fn is_valid(&self) -> bool {
+        self.start != self.end
+    }
+
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        let opens_on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        let today = time.weekday();
+        let now = time.time();
+        if self.start < self.end {
+            opens_on(today) && self.start <= now && now < self.end
+        } else {
+            (opens_on(today) && self.start <= now) || (opens_on(today.pred()) && now < self.end)
+        }
+    }
+}
+
+/// What happens to peers once they expire.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Expiry {
+    /// Moves them to their interface's archive, where they keep their
+    /// settings but no longer hold on to their addresses.
+    #[default]
+    Archive,
+    Remove,
+}
+
+/// Keeps the running interfaces in line with the peers' expiry times and
+/// access windows, checking at the start of every minute.
+pub async fn run(state: SharedState) {
+    // The peers each interface was last loaded with, by server id. Nothing
+    // is known at first, so every interface with scheduled peers is reloaded.
+    let mut loaded: HashMap<Uuid, HashSet<Uuid>> = HashMap::new();
+    loop {
+        let now = Utc::now();
+        retire_expired(&state, now).await;
+
+        // Without knowing what runs, everything is checked again next minute.
+        let running = match Wg::server_status().await {
+            Ok(running) => running,
+            Err(err) => {
+                eprintln!("{}", err);
+                wait_for_next_minute(now).await;
+                continue;
+            }
+        };
+        let state = state.read().await;
+        for (server_id, server) in state.servers.iter().enumerate() {
+            let active: HashSet<Uuid> = server
+                .peers
+                .iter()
+                .filter(|peer| peer.active(now))
+                .map(|peer| peer.id)
+                .collect();
+            let changed = match loaded.get(&server.id) {
+                Some(last) => *last != active,
+                None => server.peers.iter().any(Peer::is_scheduled),
+            };
+            if changed && running.contains(&server.name) {
+                // Keep what was loaded before so that it is tried again.
+                if let Err(err) = state.hot_reload(server_id).await {
+                    eprintln!("{}", err);
+                    continue;
+                }
+            }
+            loaded.insert(server.id, active);
+        }
+        drop(state);
+
+        wait_for_next_minute(now).await;
+    }
+}
+
+async fn wait_for_next_minute(now: DateTime<Utc>) {
+    let next = now.with_second(0).unwrap().with_nanosecond(0).unwrap() + Duration::minutes(1);
+    let wait = (next - Utc::now()).to_std().unwrap_or_default();
+    tokio::time::sleep(wait).await;
+}
+
+/// Archives or removes the peers that expired, as the settings say.
+async fn retire_expired(state: &SharedState, now: DateTime<Utc>) {
+    let mut state = state.write().await;
+    let expiry = state.expired_peers;
+    let mut retired = vec![];
+    for server in &mut state.servers {
+        let (expired, peers): (Vec<Peer>, Vec<Peer>) = std::mem::take(&mut server.peers)
+            .into_iter()
+            .partition(|peer| peer.is_expired(now));
+        server.peers = peers;
+        retired.extend(expired.iter().map(|peer| Change::Peer(peer.id)));
+        if expiry == Expiry::Archive {
+            server.archive.extend(expired);
+        }
+    }
+    if !retired.is_empty() {
+        if let Err(err) = Wg::dump_state(&state, &retired).await {
+            eprintln!("{}", err);
+        }
+    }
+}
+
+pub async fn get_archive(
+    Path(iface): Path<String>,
+    Extension(state): Extension<SharedState>,
+) -> Result<Json<Vec<Peer>>, Error> {
+    let state = state.read().await;
+    let server_id = state.server_id(&iface)?;
+    Ok(Json(state.servers[server_id].archive.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn window(days: &[Weekday], start: &str, end: &str) -> AccessWindow {
+        AccessWindow {
+            days: days.to_vec(),
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+        }
+    }
+
+    /// A time in the week of Monday, 2024-05-06.
+    fn at(day: u32, time: &str) -> DateTime<Utc> {
+        let time: NaiveTime = time.parse().unwrap();
+        Utc.with_ymd_and_hms(2024, 5, day, time.hour(), time.minute(), 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn contains_a_window_within_a_day() {
+        let window = window(&[Weekday::Mon], "09:00:00", "17:00:00");
+        assert!(window.contains(at(6, "09:00")));
+        assert!(window.contains(at(6, "16:59")));
+        assert!(!window.contains(at(6, "17:00")));
+        assert!(!window.contains(at(6, "08:59")));
+        assert!(!window.contains(at(7, "12:00")));
+    }
+
+    #[test]
+    fn contains_a_window_across_midnight() {
+        // Opens on Friday night and closes early on Saturday.
+        let window = window(&[Weekday::Fri], "22:00:00", "02:00:00");
+        assert!(window.contains(at(10, "22:00")));
+        assert!(window.contains(at(10, "23:59")));
+        assert!(window.contains(at(11, "00:00")));
+        assert!(window.contains(at(11, "01:59")));
+        assert!(!window.contains(at(11, "02:00")));
+        assert!(!window.contains(at(10, "21:59")));
+        // Saturday night is not a night the window opens on, and neither is
+        // the early morning of Friday.
+        assert!(!window.contains(at(11, "23:00")));
+        assert!(!window.contains(at(10, "01:00")));
+    }
+
+    #[test]
+    fn contains_across_the_end_of_the_week() {
+        let window = window(&[Weekday::Sun], "23:00:00", "01:00:00");
+        assert!(window.contains(at(12, "23:30")));
+        assert!(window.contains(at(13, "00:30")));
+        assert!(!window.contains(at(7, "00:30")));
+        assert!(!window.contains(at(12, "00:30")));
+    }
+
+    #[test]
+    fn contains_every_day_without_days() {
+        let window = window(&[], "22:00:00", "06:00:00");
+        for day in 6..=12 {
+            assert!(window.contains(at(day, "23:00")));
+            assert!(window.contains(at(day, "05:00")));
+            assert!(!window.contains(at(day, "12:00")));
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use crate::extract::Json;
+use crate::storage::Change;
+use crate::{error::Error, schedule::Expiry, state::SharedState, wghelper::Wg};
+use axum::{http::StatusCode, Extension};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize)]
+pub struct Settings {
+    endpoint: Option<String>,
+    detected_endpoint: Option<String>,
+    expired_peers: Expiry,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateSettings {
+    endpoint: Option<String>,
+    expired_peers: Option<Expiry>,
+}
+
+pub async fn get_settings(Extension(state): Extension<SharedState>) -> Json<Settings> {
+    let state = state.read().await;
+    Json(Settings {
+        endpoint: state.endpoint.clone(),
+        detected_endpoint: state.detected_endpoint.clone(),
+        expired_peers: state.expired_peers,
+    })
+}
+
+pub async fn update_settings(
+    Extension(state): Extension<SharedState>,
+    Json(update): Json<UpdateSettings>,
+) -> Result<StatusCode, Error> {
+    let mut state = state.write().await;
+
+    // An empty endpoint clears the setting.
+    if let Some(endpoint) = update.endpoint {
+        if endpoint.is_empty() {
+            state.endpoint = None;
+        } else if endpoint == "auto" {
+            state.detected_endpoint = Wg::detect_endpoint().await;
+            state.endpoint = Some(endpoint);
+        } else if Wg::valid_endpoint(&endpoint) {
+            state.endpoint = Some(endpoint);
+        } else {
+            return Err(Error::bad_request("Invalid endpoint"));
+        }
+    }
+
+    if let Some(expired_peers) = update.expired_peers {
+        state.expired_peers = expired_peers;
+    }
+
+    Wg::dump_state(&state, &[Change::Settings]).await?;
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,77 @@
+use crate::backend::Backend;
+use crate::netlink;
+use crate::stats::PeerStats;
+use crate::wghelper::Server;
+use async_trait::async_trait;
+use chrono::Utc;
+use ipnet::IpNet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// A stand-in for the host's WireGuard that only keeps track of which
+/// interfaces would be up and with which peers, so that the API can run
+/// without root or any WireGuard tooling. Simulated peers never connect.
+#[derive(Default)]
+pub struct Simulator {
+    /// The running interfaces by name, each with its peers by public key.
+    interfaces: Mutex<HashMap<String, HashMap<String, PeerStats>>>,
+}
+
+#[async_trait]
+impl Backend for Simulator {
+    async fn up(&self, server: &Server) -> Result<(), String> {
+        let mut interfaces = self.interfaces.lock().unwrap();
+        if interfaces.contains_key(&server.name) {
+            return Err(format!("{} already exists", server.name));
+        }
+        interfaces.insert(server.name.clone(), peers(server, HashMap::new()));
+        Ok(())
+    }
+
+    async fn down(&self, server: &Server) -> Result<(), String> {
+        let mut interfaces = self.interfaces.lock().unwrap();
+        interfaces
+            .remove(&server.name)
+            .map(|_| ())
+            .ok_or_else(|| format!("{} is not a WireGuard interface", server.name))
+    }
+
+    async fn sync(&self, server: &Server) -> Result<(), String> {
+        let mut interfaces = self.interfaces.lock().unwrap();
+        let current = interfaces
+            .get_mut(&server.name)
+            .ok_or_else(|| format!("{} is not a WireGuard interface", server.name))?;
+        *current = peers(server, std::mem::take(current));
+        Ok(())
+    }
+
+    async fn remove_route(&self, _name: &str, _cidr: &IpNet) {}
+
+    async fn interfaces(&self) -> Result<HashSet<String>, String> {
+        Ok(self.interfaces.lock().unwrap().keys().cloned().collect())
+    }
+
+    async fn stats(&self, name: &str) -> HashMap<String, PeerStats> {
+        let interfaces = self.interfaces.lock().unwrap();
+        interfaces.get(name).cloned().unwrap_or_default()
+    }
+
+    fn generate_keys(&self) -> (String, String) {
+        netlink::generate_keys()
+    }
+}
+
+/// The active peers of a server, keeping the stats of the ones that were
+/// already there.
+fn peers(server: &Server, mut current: HashMap<String, PeerStats>) -> HashMap<String, PeerStats> {
+    let now = Utc::now();
+    server
+        .peers
+        .iter()
+        .filter(|peer| peer.active(now))
+        .map(|peer| {
+            let stats = current.remove(&peer.pubkey).unwrap_or_default();
+            (peer.pubkey.clone(), stats)
+        })
+        .collect()
+}
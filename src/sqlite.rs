@@ -0,0 +1,432 @@
+use crate::persist;
+use crate::storage::{Change, Storage};
+use crate::wghelper::{Peer, Wg};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// The schema, one step per release that changed it. A database records how
+/// many of them it has had applied in its `user_version`, so only append.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE settings (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        data TEXT NOT NULL
+    );
+    CREATE TABLE servers (
+        id TEXT PRIMARY KEY,
+        position INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE peers (
+        id TEXT PRIMARY KEY,
+        server_id TEXT NOT NULL REFERENCES servers (id) ON DELETE CASCADE,
+        archived INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX peers_by_server ON peers (server_id, archived, position);
+"];
+
+/// The state in an SQLite database, with the settings, every server and
+/// every peer in a row of its own. Saving a change only writes the rows it
+/// touches, in a single transaction.
+pub struct Sqlite {
+    conn: Mutex<Connection>,
+    _lock: File,
+}
+
+/// The state laid out the way it is stored.
+#[derive(Default)]
+struct Rows {
+    settings: Option<String>,
+    servers: HashMap<Uuid, Row>,
+    peers: HashMap<Uuid, Row>,
+}
+
+struct Row {
+    /// The server of a peer, nil for a server itself.
+    server: Uuid,
+    archived: bool,
+    position: i64,
+    /// The server or peer itself, as JSON.
+    data: String,
+}
+
+impl Sqlite {
+    pub fn open(path: &str) -> Result<Sqlite, String> {
+        let lock = persist::lock(path)?;
+        let failed = |err: rusqlite::Error| format!("Failed to open {}: {}", path, err);
+        let mut conn = Connection::open(path).map_err(failed)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL; PRAGMA synchronous = FULL; PRAGMA foreign_keys = ON;",
+        )
+        .map_err(failed)?;
+        migrate(&mut conn).map_err(|err| format!("Failed to migrate {}: {}", path, err))?;
+
+        Ok(Sqlite {
+            conn: Mutex::new(conn),
+            _lock: lock,
+        })
+    }
+
+    /// Whether nothing was saved to the database yet.
+    pub fn is_empty(&self) -> Result<bool, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT NOT EXISTS (SELECT 1 FROM settings) AND NOT EXISTS (SELECT 1 FROM servers)",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(error)
+    }
+
+    /// Runs writes in a transaction of their own.
+    fn transaction<F>(&self, write: F) -> Result<(), String>
+    where
+        F: FnOnce(&Transaction) -> Result<(), String>,
+    {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(error)?;
+        write(&tx)?;
+        tx.commit().map_err(error)
+    }
+}
+
+impl Storage for Sqlite {
+    fn load(&self) -> Result<Wg, String> {
+        let rows = read(&self.conn.lock().unwrap()).map_err(error)?;
+
+        let mut state = match &rows.settings {
+            Some(settings) => parse(settings)?,
+            None => Map::new(),
+        };
+        let mut servers: Vec<(&Uuid, &Row)> = rows.servers.iter().collect();
+        servers.sort_by_key(|(_, row)| row.position);
+        let servers = servers
+            .into_iter()
+            .map(|(id, row)| {
+                let mut server = parse(&row.data)?;
+                server.insert("peers".into(), peers(&rows, id, false)?);
+                server.insert("archive".into(), peers(&rows, id, true)?);
+                Ok(Value::Object(server))
+            })
+            .collect::<Result<_, String>>()?;
+        state.insert("servers".into(), Value::Array(servers));
+
+        serde_json::from_value(Value::Object(state)).map_err(error)
+    }
+
+    /// Replaces every row, for when the whole state was loaded from elsewhere
+    /// or all of its keys changed.
+    fn save(&self, state: &Wg) -> Result<(), String> {
+        self.transaction(|tx| {
+            tx.execute_batch("DELETE FROM peers; DELETE FROM servers;")
+                .map_err(error)?;
+            let mut changes = vec![Change::Settings];
+            for server in &state.servers {
+                changes.push(Change::Server(server.id));
+                let peers = server.peers.iter().chain(&server.archive);
+                changes.extend(peers.map(|peer| Change::Peer(peer.id)));
+            }
+            write(tx, state, &changes)
+        })
+    }
+
+    fn save_changes(&self, state: &Wg, changes: &[Change]) -> Result<(), String> {
+        self.transaction(|tx| write(tx, state, changes))
+    }
+
+    /// Rewrites the database without the free pages that still hold old
+    /// rows, and empties the write-ahead log that may hold more of them.
+    fn discard_history(&self) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")
+            .map_err(error)
+    }
+}
+
+/// Applies the migrations the database has not had yet, each in a
+/// transaction of its own.
+fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(error)?;
+    let version = version as usize;
+    if version > MIGRATIONS.len() {
+        return Err(format!(
+            "schema version {} is newer than this rest-wg knows",
+            version
+        ));
+    }
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction().map_err(error)?;
+        tx.execute_batch(migration).map_err(error)?;
+        tx.pragma_update(None, "user_version", version as i64 + 1)
+            .map_err(error)?;
+        tx.commit().map_err(error)?;
+    }
+    Ok(())
+}
+
+fn read(conn: &Connection) -> rusqlite::Result<Rows> {
+    let settings = conn
+        .query_row("SELECT data FROM settings WHERE id = 0", [], |row| {
+            row.get(0)
+        })
+        .optional()?;
+
+    let mut servers = HashMap::new();
+    let mut stmt = conn.prepare("SELECT id, position, data FROM servers")?;
+    let mut query = stmt.query([])?;
+    while let Some(row) = query.next()? {
+        let id = uuid(row.get(0)?)?;
+        let server = Row {
+            server: Uuid::nil(),
+            archived: false,
+            position: row.get(1)?,
+            data: row.get(2)?,
+        };
+        servers.insert(id, server);
+    }
+
+    let mut peers = HashMap::new();
+    let mut stmt = conn.prepare("SELECT id, server_id, archived, position, data FROM peers")?;
+    let mut query = stmt.query([])?;
+    while let Some(row) = query.next()? {
+        let id = uuid(row.get(0)?)?;
+        let peer = Row {
+            server: uuid(row.get(1)?)?,
+            archived: row.get(2)?,
+            position: row.get(3)?,
+            data: row.get(4)?,
+        };
+        peers.insert(id, peer);
+    }
+
+    Ok(Rows {
+        settings,
+        servers,
+        peers,
+    })
+}
+
+/// Writes the rows of the changed parts of the state, servers before peers so
+/// that a new server is there for its peers to refer to. New rows go after
+/// the ones already there, and a peer moving in or out of the archive goes
+/// after the ones already on its side, the same as in the state.
+fn write(tx: &Transaction, state: &Wg, changes: &[Change]) -> Result<(), String> {
+    let (peers, others): (Vec<&Change>, Vec<&Change>) = changes
+        .iter()
+        .partition(|change| matches!(change, Change::Peer(_)));
+    for change in others.into_iter().chain(peers) {
+        match *change {
+            Change::Settings => {
+                let mut settings = object(serde_json::to_value(state).map_err(error)?);
+                settings.remove("servers");
+                tx.execute(
+                    "INSERT INTO settings (id, data) VALUES (0, ?1)
+                     ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+                    params![Value::Object(settings).to_string()],
+                )
+                .map_err(error)?;
+            }
+            Change::Server(id) => match state.servers.iter().find(|server| server.id == id) {
+                Some(server) => {
+                    let mut data = object(serde_json::to_value(server).map_err(error)?);
+                    data.remove("peers");
+                    data.remove("archive");
+                    tx.execute(
+                        "INSERT INTO servers (id, position, name, data)
+                         VALUES (?1, (SELECT COALESCE(MAX(position), -1) + 1 FROM servers), ?2, ?3)
+                         ON CONFLICT (id) DO UPDATE SET name = excluded.name, data = excluded.data",
+                        params![id.to_string(), server.name, Value::Object(data).to_string()],
+                    )
+                    .map_err(error)?;
+                }
+                None => {
+                    tx.execute("DELETE FROM servers WHERE id = ?1", params![id.to_string()])
+                        .map_err(error)?;
+                }
+            },
+            Change::Peer(id) => match find_peer(state, id) {
+                Some((server, archived, peer)) => {
+                    let data = serde_json::to_string(peer).map_err(error)?;
+                    tx.execute(
+                        "INSERT INTO peers (id, server_id, archived, position, name, data)
+                         VALUES (?1, ?2, ?3, (SELECT COALESCE(MAX(position), -1) + 1 FROM peers), ?4, ?5)
+                         ON CONFLICT (id) DO UPDATE SET
+                            position = CASE
+                                WHEN archived = excluded.archived THEN position
+                                ELSE excluded.position
+                            END,
+                            archived = excluded.archived, name = excluded.name, data = excluded.data",
+                        params![id.to_string(), server.to_string(), archived, peer.name, data],
+                    )
+                    .map_err(error)?;
+                }
+                None => {
+                    tx.execute("DELETE FROM peers WHERE id = ?1", params![id.to_string()])
+                        .map_err(error)?;
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Finds a peer by its id, with the id of its server and whether it is
+/// archived.
+fn find_peer(state: &Wg, id: Uuid) -> Option<(Uuid, bool, &Peer)> {
+    state.servers.iter().find_map(|server| {
+        let live = server.peers.iter().map(|peer| (false, peer));
+        let archived = server.archive.iter().map(|peer| (true, peer));
+        live.chain(archived)
+            .find(|(_, peer)| peer.id == id)
+            .map(|(archived, peer)| (server.id, archived, peer))
+    })
+}
+
+/// The peers of a server in their order, as JSON.
+fn peers(rows: &Rows, server: &Uuid, archived: bool) -> Result<Value, String> {
+    let mut peers: Vec<&Row> = rows
+        .peers
+        .values()
+        .filter(|row| row.server == *server && row.archived == archived)
+        .collect();
+    peers.sort_by_key(|row| row.position);
+    peers
+        .into_iter()
+        .map(|row| parse(&row.data).map(Value::Object))
+        .collect::<Result<_, _>>()
+        .map(Value::Array)
+}
+
+fn parse(data: &str) -> Result<Map<String, Value>, String> {
+    serde_json::from_str(data).map_err(error)
+}
+
+fn object(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    }
+}
+
+fn uuid(id: String) -> rusqlite::Result<Uuid> {
+    Uuid::parse_str(&id)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(err)))
+}
+
+fn error(err: impl Display) -> String {
+    format!("SQLite storage: {}", err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use crate::wghelper::Server;
+
+    fn database(name: &str) -> Sqlite {
+        let path = testutil::scratch_dir(name).join("interfaces.db");
+        Sqlite::open(path.to_str().unwrap()).unwrap()
+    }
+
+    /// A server with the given peers on consecutive addresses.
+    fn server(name: &str, peers: &[&str]) -> Server {
+        let peers: String = peers
+            .iter()
+            .zip(2..)
+            .map(|(peer, host)| testutil::peer(peer, host))
+            .collect();
+        testutil::server(name, &peers)
+    }
+
+    fn names(state: &Wg) -> Vec<(String, Vec<String>, Vec<String>)> {
+        let names = |peers: &[Peer]| peers.iter().map(|peer| peer.name.clone()).collect();
+        state
+            .servers
+            .iter()
+            .map(|server| {
+                (
+                    server.name.clone(),
+                    names(&server.peers),
+                    names(&server.archive),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn changes_are_saved_row_by_row() {
+        let db = database("changes");
+        let mut state = Wg::default();
+        state.servers.push(server("wg0", &["a", "b", "c"]));
+        db.save(&state).unwrap();
+
+        // A new server goes after the others, and a new peer after its own.
+        state.servers.push(server("wg1", &[]));
+        let peer = server("wg1", &["d"]).peers.remove(0);
+        let new_peer = peer.id;
+        state.servers[0].peers.push(peer);
+        let changes = [Change::Server(state.servers[1].id), Change::Peer(new_peer)];
+        db.save_changes(&state, &changes).unwrap();
+
+        // Archiving moves a peer to the end of the archive, and deleting
+        // one removes its row.
+        let archived = state.servers[0].peers.remove(0);
+        let deleted = state.servers[0].peers.remove(0);
+        let changes = [Change::Peer(archived.id), Change::Peer(deleted.id)];
+        state.servers[0].archive.push(archived);
+        db.save_changes(&state, &changes).unwrap();
+
+        let loaded = db.load().unwrap();
+        assert_eq!(names(&loaded), names(&state));
+        assert_eq!(
+            names(&loaded)[0],
+            ("wg0".into(), vec!["c".into(), "d".into()], vec!["a".into()])
+        );
+    }
+
+    #[test]
+    fn deleting_a_server_deletes_its_peers() {
+        let db = database("delete");
+        let mut state = Wg::default();
+        state.servers.push(server("wg0", &["a"]));
+        state.servers.push(server("wg1", &["b"]));
+        db.save(&state).unwrap();
+
+        let server = state.servers.remove(0);
+        db.save_changes(&state, &[Change::Server(server.id)])
+            .unwrap();
+        assert_eq!(names(&db.load().unwrap()), names(&state));
+        let conn = db.conn.lock().unwrap();
+        let peers: i64 = conn
+            .query_row("SELECT COUNT(*) FROM peers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(peers, 1);
+    }
+
+    #[test]
+    fn settings_are_saved_on_their_own() {
+        let db = database("settings");
+        let mut state = Wg::default();
+        state.servers.push(server("wg0", &["a"]));
+        db.save(&state).unwrap();
+
+        state.endpoint = Some("vpn.example.com".into());
+        state.servers[0].port = 51821;
+        db.save_changes(&state, &[Change::Settings]).unwrap();
+        let loaded = db.load().unwrap();
+        assert_eq!(loaded.endpoint.as_deref(), Some("vpn.example.com"));
+        // Only the settings were saved.
+        assert_eq!(loaded.servers[0].port, 51820);
+    }
+}
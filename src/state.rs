@@ -1,6 +1,8 @@
 use std::sync::Arc;
 
+use crate::traffic::Traffic;
 use crate::wghelper::Wg;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 
 pub type SharedState = Arc<RwLock<Wg>>;
+pub type SharedTraffic = Arc<Mutex<Traffic>>;
@@ -0,0 +1,118 @@
+use crate::backend;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A peer counts as online if it completed a handshake within this many
+/// seconds. WireGuard renews sessions every two minutes while traffic flows.
+const ONLINE_TIMEOUT: u64 = 180;
+
+/// What the kernel reports about one peer of a running interface.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PeerStats {
+    pub endpoint: Option<String>,
+    /// Unix time of the latest handshake.
+    pub latest_handshake: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub persistent_keepalive: Option<u16>,
+    pub online: bool,
+}
+
+/// Reads the peers of a running interface, keyed by public key. An interface
+/// that is not running has no peers.
+pub async fn dump(name: &str) -> HashMap<String, PeerStats> {
+    backend::get().stats(name).await
+}
+
+/// Parses the output of `wg show <iface> dump`. The first line describes the
+/// interface and every further line one peer, with tab separated fields:
+/// public key, preshared key, endpoint, allowed IPs, latest handshake,
+/// received bytes, sent bytes and persistent keepalive.
+pub fn parse(dump: &str, now: u64) -> HashMap<String, PeerStats> {
+    dump.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 8 {
+                return None;
+            }
+
+            let latest_handshake = fields[4].parse().ok().filter(|time| *time != 0);
+            let stats = PeerStats {
+                endpoint: Some(fields[2].to_string()).filter(|endpoint| endpoint != "(none)"),
+                latest_handshake,
+                rx_bytes: fields[5].parse().unwrap_or(0),
+                tx_bytes: fields[6].parse().unwrap_or(0),
+                persistent_keepalive: fields[7].parse().ok(),
+                online: is_online(latest_handshake, now),
+            };
+            Some((fields[0].to_string(), stats))
+        })
+        .collect()
+}
+
+pub fn is_online(latest_handshake: Option<u64>, now: u64) -> bool {
+    latest_handshake.is_some_and(|time| now.saturating_sub(time) < ONLINE_TIMEOUT)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMP: &str = "\
+cHJpdmF0ZQ==\tcHVibGlj\t51820\toff
+YWxpY2U=\t(none)\t203.0.113.7:40123\t10.8.0.2/32\t1000\t2048\t4096\t25
+Ym9i\t(none)\t(none)\t10.8.0.3/32\t0\t0\t0\toff
+Y2Fyb2w=\t(none)\t198.51.100.1:51820\t10.8.0.4/32\t500\t1\t2\toff
+";
+
+    #[test]
+    fn parse_reads_every_peer() {
+        let peers = parse(DUMP, 1100);
+        assert_eq!(peers.len(), 3);
+
+        let alice = &peers["YWxpY2U="];
+        assert_eq!(alice.endpoint.as_deref(), Some("203.0.113.7:40123"));
+        assert_eq!(alice.latest_handshake, Some(1000));
+        assert_eq!(alice.rx_bytes, 2048);
+        assert_eq!(alice.tx_bytes, 4096);
+        assert_eq!(alice.persistent_keepalive, Some(25));
+        assert!(alice.online);
+
+        // A peer that never connected has no endpoint and no handshake.
+        let bob = &peers["Ym9i"];
+        assert_eq!(bob.endpoint, None);
+        assert_eq!(bob.latest_handshake, None);
+        assert_eq!(bob.persistent_keepalive, None);
+        assert!(!bob.online);
+
+        // A handshake older than the timeout is offline.
+        assert!(!peers["Y2Fyb2w="].online);
+    }
+
+    #[test]
+    fn parse_skips_the_interface_and_short_lines() {
+        assert!(parse("", 0).is_empty());
+        assert!(parse("cHJpdmF0ZQ==\tcHVibGlj\t51820\toff\n", 0).is_empty());
+        assert!(parse("header\nYWxpY2U=\t(none)\t(none)\n", 0).is_empty());
+    }
+
+    #[test]
+    fn is_online_within_the_timeout() {
+        assert!(is_online(Some(1000), 1000 + ONLINE_TIMEOUT - 1));
+        assert!(!is_online(Some(1000), 1000 + ONLINE_TIMEOUT));
+        // A clock that went backwards does not take a peer offline.
+        assert!(is_online(Some(1000), 900));
+        assert!(!is_online(None, 1000));
+    }
+}
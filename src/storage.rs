@@ -0,0 +1,298 @@
+use crate::persist;
+use crate::sqlite::Sqlite;
+use crate::wghelper::Wg;
+use std::fs::File;
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+pub const TOML_PATH: &str = "./interfaces.toml";
+pub const SQLITE_PATH: &str = "./interfaces.db";
+
+/// The first and last lines of every state file rest-wg writes. A file that
+/// starts with the first but does not end with the last was cut short.
+const HEADER: &str = "# Written by rest-wg, complete only if it ends with the line below.\n";
+const TRAILER: &str = "# End of the rest-wg state.\n";
+
+/// Where the state lives between runs.
+pub trait Storage: Send + Sync {
+    /// Loads the state, starting out empty if none was saved yet.
+    fn load(&self) -> Result<Wg, String>;
+
+    /// Saves the state as it is now, all of it or none of it.
+    fn save(&self, state: &Wg) -> Result<(), String>;
+
+    /// Saves the parts of the state that changed, all of them or none of
+    /// them. A storage that keeps the state in one piece saves all of it.
+    fn save_changes(&self, state: &Wg, changes: &[Change]) -> Result<(), String> {
+        let _ = changes;
+        self.save(state)
+    }
+
+    /// Drops the earlier versions of the state the storage keeps around,
+    /// once the keys in them should no longer be readable.
+    fn discard_history(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A part of the state to save, as it is in the state now. A server or peer
+/// that is no longer there is deleted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    /// The settings that are not about any one server.
+    Settings,
+    /// A server's own settings, without its peers.
+    Server(Uuid),
+    /// A peer, whether it is in use or archived.
+    Peer(Uuid),
+}
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+
+/// Opens the storage for the rest of the run: nothing for a dry run, the
+/// SQLite database, or else the TOML file. Either file is locked so that no
+/// other instance writes it.
+pub fn init(dry_run: bool, sqlite: bool) -> Result<(), String> {
+    let storage: Box<dyn Storage> = if dry_run {
+        Box::new(Memory)
+    } else if sqlite {
+        Box::new(Sqlite::open(SQLITE_PATH)?)
+    } else {
+        Box::new(TomlFile::open(TOML_PATH)?)
+    };
+    let _ = STORAGE.set(storage);
+    Ok(())
+}
+
+pub fn get() -> &'static dyn Storage {
+    STORAGE
+        .get()
+        .expect("storage is opened at startup")
+        .as_ref()
+}
+
+/// Keeps nothing between runs, so that a dry run starts out empty and leaves
+/// the state of a real instance in the same directory alone.
+pub struct Memory;
+
+impl Storage for Memory {
+    fn load(&self) -> Result<Wg, String> {
+        Ok(Wg::default())
+    }
+
+    fn save(&self, _state: &Wg) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Copies the state from the TOML file into an empty SQLite database.
+pub fn migrate() -> Result<(), String> {
+    let state = TomlFile::open(TOML_PATH)?.load()?;
+    let sqlite = Sqlite::open(SQLITE_PATH)?;
+    if !sqlite.is_empty()? {
+        return Err(format!("{} already holds a state", SQLITE_PATH));
+    }
+    sqlite.save(&state)?;
+
+    let peers: usize = state.servers.iter().map(|server| server.peers.len()).sum();
+    println!(
+        "Migrated {} interfaces with {} peers from {} to {}, start with --sqlite to use it",
+        state.servers.len(),
+        peers,
+        TOML_PATH,
+        SQLITE_PATH
+    );
+    Ok(())
+}
+
+/// The whole state in one TOML file, rewritten on every save. Each save
+/// replaces the file atomically and keeps the one before as a backup.
+pub struct TomlFile {
+    path: &'static str,
+    _lock: File,
+}
+
+impl TomlFile {
+    pub fn open(path: &'static str) -> Result<TomlFile, String> {
+        Ok(TomlFile {
+            path,
+            _lock: persist::lock(path)?,
+        })
+    }
+
+    /// Parses a state file. Every state file that was written in full has at
+    /// least one setting, so an empty one has been cut short. Files written
+    /// before the header was added, or by hand without it, are taken as
+    /// they are.
+    fn parse(&self, config: &str) -> Result<Wg, String> {
+        if config.trim().is_empty() {
+            return Err(format!("{} is empty", self.path));
+        }
+        if config.starts_with(HEADER) && !config.trim_end().ends_with(TRAILER.trim_end()) {
+            return Err(format!("{} was cut short", self.path));
+        }
+        toml::from_str(config).map_err(|err| format!("Failed to parse {}: {}", self.path, err))
+    }
+
+    fn render(state: &Wg) -> Result<String, String> {
+        let config = toml::to_string(state).map_err(|err| err.to_string())?;
+        Ok(format!("{}{}{}", HEADER, config, TRAILER))
+    }
+
+    /// Finds the newest backup that loads, keeping the broken state file
+    /// aside for inspection.
+    fn recover(&self, err: String) -> Result<(String, Wg), String> {
+        for backup in persist::backups(self.path) {
+            let config = match std::fs::read_to_string(&backup) {
+                Ok(config) => config,
+                Err(_) => continue,
+            };
+            if let Ok(state) = self.parse(&config) {
+                eprintln!("{}, recovering from {}", err, backup);
+                let broken = format!("{}.broken", self.path);
+                std::fs::rename(self.path, &broken)
+                    .map_err(|err| format!("Failed to move {} aside: {}", self.path, err))?;
+                // With no file left to compare against, the recovered state
+                // is always written back.
+                return Ok((String::new(), state));
+            }
+        }
+        Err(format!("{}, and there is no backup to recover from", err))
+    }
+}
+
+impl Storage for TomlFile {
+    /// A state file that is cut short or otherwise unreadable is replaced by
+    /// the newest backup that still loads.
+    fn load(&self) -> Result<Wg, String> {
+        let config = match std::fs::read_to_string(self.path) {
+            Ok(config) => config,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Wg::default()),
+            Err(err) => return Err(format!("Failed to read {}: {}", self.path, err)),
+        };
+        let (config, mut state) = match self.parse(&config) {
+            Ok(state) => (config, state),
+            Err(err) => self.recover(err)?,
+        };
+        state.assign_ids();
+
+        // Older state files are upgraded while they are loaded, so write them
+        // back in the current format.
+        let migrated = TomlFile::render(&state)?;
+        if migrated != config {
+            persist::write(self.path, migrated.as_bytes())
+                .map_err(|err| format!("Failed to write {}: {}", self.path, err))?;
+        }
+        Ok(state)
+    }
+
+    fn save(&self, state: &Wg) -> Result<(), String> {
+        let config = TomlFile::render(state)?;
+        persist::write(self.path, config.as_bytes())
+            .map_err(|err| format!("Failed to write {}: {}", self.path, err))
+    }
+
+    /// Deletes the backups and the file a failed load set aside.
+    fn discard_history(&self) -> Result<(), String> {
+        let failed =
+            |err: std::io::Error| format!("Failed to remove the backups of {}: {}", self.path, err);
+        persist::remove_backups(self.path).map_err(failed)?;
+        match std::fs::remove_file(format!("{}.broken", self.path)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(failed(err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    /// A state file in a directory of its own.
+    fn toml_file(name: &str) -> TomlFile {
+        let path = testutil::scratch_dir(name).join("interfaces.toml");
+        let path = path.to_string_lossy().into_owned();
+        TomlFile::open(Box::leak(path.into_boxed_str())).unwrap()
+    }
+
+    fn state(endpoint: &str) -> Wg {
+        Wg {
+            endpoint: Some(endpoint.into()),
+            ..Wg::default()
+        }
+    }
+
+    #[test]
+    fn a_cut_short_file_is_recovered_from_its_backup() {
+        let file = toml_file("cut-short");
+        file.save(&state("old.example.com")).unwrap();
+        file.save(&state("new.example.com")).unwrap();
+        assert_eq!(
+            file.load().unwrap().endpoint.as_deref(),
+            Some("new.example.com")
+        );
+
+        // Every line that is left still parses, but the trailer is gone.
+        let config = std::fs::read_to_string(file.path).unwrap();
+        let cut = config.lines().take(2).collect::<Vec<_>>().join("\n");
+        std::fs::write(file.path, cut).unwrap();
+        assert!(file
+            .parse(&std::fs::read_to_string(file.path).unwrap())
+            .is_err());
+
+        assert_eq!(
+            file.load().unwrap().endpoint.as_deref(),
+            Some("old.example.com")
+        );
+        assert!(std::path::Path::new(&format!("{}.broken", file.path)).exists());
+    }
+
+    #[test]
+    fn the_file_and_its_backups_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file = toml_file("private");
+        // As written before rest-wg took care of the permissions.
+        std::fs::write(file.path, "").unwrap();
+        let readable = std::fs::Permissions::from_mode(0o644);
+        std::fs::set_permissions(file.path, readable).unwrap();
+        file.save(&state("a.example.com")).unwrap();
+
+        for path in [file.path.to_string(), format!("{}.1", file.path)] {
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", path);
+        }
+    }
+
+    #[test]
+    fn discarding_the_history_removes_every_old_copy() {
+        let file = toml_file("discard");
+        for endpoint in ["a.example.com", "b.example.com", "c.example.com"] {
+            file.save(&state(endpoint)).unwrap();
+        }
+        std::fs::write(format!("{}.broken", file.path), "").unwrap();
+        file.discard_history().unwrap();
+
+        let dir = std::path::Path::new(file.path).parent().unwrap();
+        let mut left: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["interfaces.toml", "interfaces.toml.lock"]);
+    }
+
+    #[test]
+    fn files_without_the_header_still_load() {
+        let file = toml_file("legacy");
+        std::fs::write(file.path, "endpoint = \"vpn.example.com\"\n").unwrap();
+        assert_eq!(
+            file.load().unwrap().endpoint.as_deref(),
+            Some("vpn.example.com")
+        );
+        // They are written back with the header and trailer.
+        let config = std::fs::read_to_string(file.path).unwrap();
+        assert!(config.starts_with(HEADER) && config.ends_with(TRAILER));
+    }
+}
@@ -0,0 +1,131 @@
+use crate::wghelper::{Peer, Server};
+use chrono::Utc;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+/// Caps on a peer's bandwidth in kbit/s, as seen from the peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub download: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub upload: Option<u32>,
+}
+
+impl RateLimit {
+    pub fn is_valid(&self) -> bool {
+        self.download != Some(0) && self.upload != Some(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.download.is_none() && self.upload.is_none()
+    }
+}
+
+/// Replaces the shaping rules of a running interface with ones for its
+/// current peers. Downloads are shaped by an HTB class per peer on the way
+/// out of the interface, uploads are policed on the way in. Traffic of peers
+/// without a limit is left alone. A rule that fails does not keep the others
+/// from being installed.
+pub async fn apply(server: &Server) -> Result<(), String> {
+    remove(server).await;
+
+    let now = Utc::now();
+    let limited: Vec<(&Peer, RateLimit)> = server
+        .peers
+        .iter()
+        .filter(|peer| peer.active(now))
+        .filter_map(|peer| Some((peer, peer.rate_limit?)))
+        .collect();
+    let dev = server.name.as_str();
+    let mut errors = vec![];
+
+    if limited.iter().any(|(_, limit)| limit.download.is_some()) {
+        let root = ["qdisc", "add", "dev", dev, "root", "handle", "1:", "htb"];
+        errors.extend(tc(&root).await.err());
+    }
+    if limited.iter().any(|(_, limit)| limit.upload.is_some()) {
+        let ingress = ["qdisc", "add", "dev", dev, "handle", "ffff:", "ingress"];
+        errors.extend(tc(&ingress).await.err());
+    }
+
+    for (index, (peer, limit)) in limited.iter().enumerate() {
+        if let Some(rate) = limit.download {
+            let class = format!("1:{:x}", index + 1);
+            let rate = format!("{}kbit", rate);
+            let add_class = [
+                "class", "add", "dev", dev, "parent", "1:", "classid", &class, "htb", "rate",
+                &rate, "ceil", &rate,
+            ];
+            errors.extend(tc(&add_class).await.err());
+            for route in peer.routes() {
+                let mut args = vec!["filter", "add", "dev", dev, "parent", "1:"];
+                let matches = matches(&route, "dst");
+                args.extend(matches.iter().map(String::as_str));
+                args.extend(["flowid", &class]);
+                errors.extend(tc(&args).await.err());
+            }
+        }
+
+        if let Some(rate) = limit.upload {
+            let burst = format!("{}k", burst(rate));
+            let rate = format!("{}kbit", rate);
+            for route in peer.routes() {
+                let mut args = vec!["filter", "add", "dev", dev, "parent", "ffff:"];
+                let matches = matches(&route, "src");
+                args.extend(matches.iter().map(String::as_str));
+                args.extend([
+                    "police", "rate", &rate, "burst", &burst, "drop", "flowid", ":1",
+                ]);
+                errors.extend(tc(&args).await.err());
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors.concat())
+}
+
+/// Removes the shaping rules of an interface, whether it has any or not.
+pub async fn remove(server: &Server) {
+    let dev = server.name.as_str();
+    let _ = tc(&["qdisc", "del", "dev", dev, "root"]).await;
+    let _ = tc(&["qdisc", "del", "dev", dev, "ingress"]).await;
+}
+
+/// The u32 match for traffic to or from a network.
+fn matches(network: &IpNet, direction: &str) -> Vec<String> {
+    let (protocol, prio, field) = match network {
+        IpNet::V4(_) => ("ip", "1", "ip"),
+        IpNet::V6(_) => ("ipv6", "2", "ip6"),
+    };
+    [
+        "protocol", protocol, "prio", prio, "u32", "match", field, direction,
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .chain([network.to_string()])
+    .collect()
+}
+
+/// The burst in kbytes a policer allows at a rate: a tenth of a second's
+/// worth, but no less than 10k so that full size packets get through.
+fn burst(rate: u32) -> u32 {
+    (rate / 80).max(10)
+}
+
+async fn tc(args: &[&str]) -> Result<(), String> {
+    let output = Command::new("tc")
+        .args(args)
+        .output()
+        .await
+        .map_err(|err| format!("Failed to execute tc: {}", err))?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(String::from_utf8_lossy(&output.stderr).into_owned())
+}
@@ -0,0 +1,37 @@
+use crate::wghelper::Server;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// An empty directory for a test of its own, so that tests running side by
+/// side do not share files.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rest-wg-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A server on 10.8.0.1/24, with whatever else the TOML given sets on it and
+/// its peers. Every server and peer gets an id of its own.
+pub fn server(name: &str, toml: &str) -> Server {
+    let config = format!(
+        "path = \"/tmp/{0}.conf\"\nname = \"{0}\"\naddress = \"10.8.0.1/24\"\n\
+         port = 51820\nprikey = \"\"\npubkey = \"\"\n{1}",
+        name, toml
+    );
+    let mut server: Server = toml::from_str(&config).unwrap();
+    server.id = Uuid::new_v4();
+    for peer in &mut server.peers {
+        peer.id = Uuid::new_v4();
+    }
+    server
+}
+
+/// The TOML of an enabled peer on 10.8.0.`host`, for `server`.
+pub fn peer(name: &str, host: u8) -> String {
+    format!(
+        "[[peers]]\nname = \"{}\"\naddress = \"10.8.0.{}/24\"\n\
+         prikey = \"\"\npubkey = \"\"\nenabled = true\n",
+        name, host
+    )
+}
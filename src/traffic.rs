@@ -0,0 +1,363 @@
+use crate::error::Error;
+use crate::extract::{Json, Path, Query};
+use crate::state::{SharedState, SharedTraffic};
+use crate::{persist, quota, stats, wghelper::Wg};
+use axum::Extension;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+const PATH: &str = "./traffic.json";
+
+/// How often, in seconds, the counters of running interfaces are sampled.
+const INTERVAL: u64 = 60;
+
+/// How long hourly and daily totals are kept. Monthly totals are kept for good.
+const HOURS_KEPT: i64 = 31;
+const DAYS_KEPT: i64 = 400;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.rx_bytes += other.rx_bytes;
+        self.tx_bytes += other.tx_bytes;
+    }
+}
+
+/// The recorded traffic of one peer. It outlives the peer, so an interface's
+/// history still includes the peers that were deleted from it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeerTraffic {
+    pub server: Uuid,
+    /// The counters `wg` reported at the last sample, or `None` if the peer
+    /// was not on a running interface then.
+    #[serde(default)]
+    last: Option<Usage>,
+    #[serde(default)]
+    hourly: BTreeMap<String, Usage>,
+    #[serde(default)]
+    daily: BTreeMap<String, Usage>,
+    #[serde(default)]
+    monthly: BTreeMap<String, Usage>,
+}
+
+impl PeerTraffic {
+    fn buckets(&self, period: Period) -> &BTreeMap<String, Usage> {
+        match period {
+            Period::Hour => &self.hourly,
+            Period::Day => &self.daily,
+            Period::Month => &self.monthly,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Hour,
+    #[default]
+    Day,
+    Month,
+}
+
+impl Period {
+    /// The UTC bucket a point in time falls into, in a form that sorts
+    /// chronologically.
+    pub fn bucket(self, time: DateTime<Utc>) -> String {
+        match self {
+            Period::Hour => time.format("%Y-%m-%dT%H:00Z"),
+            Period::Day => time.format("%Y-%m-%d"),
+            Period::Month => time.format("%Y-%m"),
+        }
+        .to_string()
+    }
+}
+
+/// Per-peer traffic totals, saved to a file of their own.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Traffic {
+    #[serde(default)]
+    peers: HashMap<Uuid, PeerTraffic>,
+    /// Where the totals are saved, or nowhere for a dry run.
+    #[serde(skip)]
+    path: Option<&'static str>,
+}
+
+impl Traffic {
+    /// Loads the recorded traffic, starting from nothing if none was recorded.
+    /// A file that does not parse is kept aside for inspection rather than
+    /// overwritten by the next save.
+    pub fn read() -> Result<Traffic, String> {
+        let traffic = match std::fs::read_to_string(PATH) {
+            Ok(traffic) => traffic,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(format!("Failed to read {}: {}", PATH, err)),
+        };
+        let traffic = match serde_json::from_str(&traffic) {
+            Ok(traffic) => traffic,
+            Err(_) if traffic.is_empty() => Traffic::default(),
+            Err(err) => {
+                let broken = format!("{}.broken", PATH);
+                std::fs::rename(PATH, &broken)
+                    .map_err(|err| format!("Failed to move {} aside: {}", PATH, err))?;
+                eprintln!(
+                    "Failed to parse {}: {}, starting over and keeping it as {}",
+                    PATH, err, broken
+                );
+                Traffic::default()
+            }
+        };
+        Ok(Traffic {
+            path: Some(PATH),
+            ..traffic
+        })
+    }
+
+    /// Saves the recorded traffic, replacing the file atomically like the
+    /// state.
+    pub fn save(&self) -> Result<(), String> {
+        let path = match self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let traffic = serde_json::to_string(self).map_err(|err| err.to_string())?;
+        tokio::task::block_in_place(|| persist::write(path, traffic.as_bytes()))
+            .map_err(|err| format!("Failed to write {}: {}", path, err))
+    }
+
+    /// Adds what a peer transferred since the last sample, given the counters
+    /// `wg` reports for it now, if any.
+    pub fn record(
+        &mut self,
+        server: Uuid,
+        peer: Uuid,
+        counters: Option<Usage>,
+        time: DateTime<Utc>,
+    ) {
+        let traffic = self.peers.entry(peer).or_default();
+        traffic.server = server;
+        let last = std::mem::replace(&mut traffic.last, counters);
+        let counters = match counters {
+            Some(counters) => counters,
+            None => return,
+        };
+
+        // The counters start from zero whenever the interface comes up or the
+        // peer is added back to it. If they went down, or the peer was not
+        // seen at the last sample, everything they hold is new.
+        let delta = match last {
+            Some(last)
+                if counters.rx_bytes >= last.rx_bytes && counters.tx_bytes >= last.tx_bytes =>
+            {
+                Usage {
+                    rx_bytes: counters.rx_bytes - last.rx_bytes,
+                    tx_bytes: counters.tx_bytes - last.tx_bytes,
+                }
+            }
+            _ => counters,
+        };
+        if delta == Usage::default() {
+            return;
+        }
+
+        let hour = Period::Hour.bucket(time);
+        traffic.hourly.entry(hour).or_default().add(delta);
+        let day = Period::Day.bucket(time);
+        traffic.daily.entry(day).or_default().add(delta);
+        let month = Period::Month.bucket(time);
+        traffic.monthly.entry(month).or_default().add(delta);
+    }
+
+    /// What a peer transferred in the buckets of a period from the one `since`
+    /// falls into on.
+    pub fn since(&self, peer: Uuid, period: Period, since: DateTime<Utc>) -> Usage {
+        let mut usage = Usage::default();
+        if let Some(traffic) = self.peers.get(&peer) {
+            for bucket in traffic.buckets(period).range(period.bucket(since)..) {
+                usage.add(*bucket.1);
+            }
+        }
+        usage
+    }
+
+    /// Drops the hourly and daily totals that are past keeping.
+    pub fn prune(&mut self, time: DateTime<Utc>) {
+        let hours = Period::Hour.bucket(time - Duration::days(HOURS_KEPT));
+        let days = Period::Day.bucket(time - Duration::days(DAYS_KEPT));
+        for traffic in self.peers.values_mut() {
+            traffic.hourly.retain(|bucket, _| *bucket >= hours);
+            traffic.daily.retain(|bucket, _| *bucket >= days);
+        }
+    }
+
+    /// The totals per period of the peers matching the filter, oldest first.
+    fn totals<F>(&self, period: Period, filter: F) -> Vec<Total>
+    where
+        F: Fn(&Uuid, &PeerTraffic) -> bool,
+    {
+        let mut totals: BTreeMap<&str, Usage> = BTreeMap::new();
+        for (peer, traffic) in &self.peers {
+            if !filter(peer, traffic) {
+                continue;
+            }
+            for (bucket, usage) in traffic.buckets(period) {
+                totals.entry(bucket).or_default().add(*usage);
+            }
+        }
+        totals
+            .into_iter()
+            .map(|(period, usage)| Total {
+                period: period.to_string(),
+                usage,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Total {
+    period: String,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TrafficQuery {
+    #[serde(default)]
+    period: Period,
+}
+
+/// Samples the counters of every running interface forever, enforcing
+/// quotas after each sample.
+pub async fn sample(shared_state: SharedState, shared_traffic: SharedTraffic) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(INTERVAL));
+    loop {
+        interval.tick().await;
+
+        let running = match Wg::server_status().await {
+            Ok(running) => running,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        let state = shared_state.read().await;
+        let mut traffic = shared_traffic.lock().await;
+        let time = Utc::now();
+        for server in &state.servers {
+            let dump = if running.contains(&server.name) {
+                stats::dump(&server.name).await
+            } else {
+                HashMap::new()
+            };
+            for peer in &server.peers {
+                let counters = dump.get(&peer.pubkey).map(|stats| Usage {
+                    rx_bytes: stats.rx_bytes,
+                    tx_bytes: stats.tx_bytes,
+                });
+                traffic.record(server.id, peer.id, counters, time);
+            }
+        }
+        traffic.prune(time);
+        if let Err(err) = traffic.save() {
+            eprintln!("{}", err);
+        }
+        drop(traffic);
+        drop(state);
+
+        quota::enforce(&shared_state, &shared_traffic).await;
+    }
+}
+
+pub async fn get_server_traffic(
+    Path(iface): Path<String>,
+    Query(query): Query<TrafficQuery>,
+    Extension(state): Extension<SharedState>,
+    Extension(traffic): Extension<SharedTraffic>,
+) -> Result<Json<Vec<Total>>, Error> {
+    let state = state.read().await;
+    let server_id = state.server_id(&iface)?;
+    let server = state.servers[server_id].id;
+
+    let traffic = traffic.lock().await;
+    Ok(Json(traffic.totals(query.period, |_, traffic| {
+        traffic.server == server
+    })))
+}
+
+pub async fn get_peer_traffic(
+    Path((iface, peer)): Path<(String, String)>,
+    Query(query): Query<TrafficQuery>,
+    Extension(state): Extension<SharedState>,
+    Extension(traffic): Extension<SharedTraffic>,
+) -> Result<Json<Vec<Total>>, Error> {
+    let state = state.read().await;
+    let server_id = state.server_id(&iface)?;
+    let peer_id = state.peer_id(server_id, &peer)?;
+    let peer = state.servers[server_id].peers[peer_id].id;
+
+    let traffic = traffic.lock().await;
+    Ok(Json(traffic.totals(query.period, |id, _| *id == peer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn usage(rx_bytes: u64, tx_bytes: u64) -> Usage {
+        Usage { rx_bytes, tx_bytes }
+    }
+
+    fn total(traffic: &Traffic, peer: Uuid) -> Usage {
+        traffic.since(peer, Period::Month, Utc.timestamp_opt(0, 0).unwrap())
+    }
+
+    #[test]
+    fn record_adds_what_the_counters_grew_by() {
+        let (server, peer) = (Uuid::new_v4(), Uuid::new_v4());
+        let time = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let mut traffic = Traffic::default();
+
+        traffic.record(server, peer, Some(usage(100, 10)), time);
+        traffic.record(server, peer, Some(usage(150, 30)), time);
+        assert_eq!(total(&traffic, peer), usage(150, 30));
+    }
+
+    #[test]
+    fn record_counts_everything_after_a_counter_reset() {
+        let (server, peer) = (Uuid::new_v4(), Uuid::new_v4());
+        let time = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let mut traffic = Traffic::default();
+
+        traffic.record(server, peer, Some(usage(1000, 1000)), time);
+        // The interface restarted, so the counters began again from zero.
+        traffic.record(server, peer, Some(usage(40, 2000)), time);
+        assert_eq!(total(&traffic, peer), usage(1040, 3000));
+
+        // The peer was gone for a sample, and came back with new counters.
+        traffic.record(server, peer, None, time);
+        traffic.record(server, peer, Some(usage(5000, 5000)), time);
+        assert_eq!(total(&traffic, peer), usage(6040, 8000));
+    }
+
+    #[test]
+    fn record_fills_the_buckets_of_the_sample_time() {
+        let (server, peer) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut traffic = Traffic::default();
+        let first = Utc.with_ymd_and_hms(2024, 5, 31, 23, 30, 0).unwrap();
+        let second = Utc.with_ymd_and_hms(2024, 6, 1, 0, 30, 0).unwrap();
+
+        traffic.record(server, peer, Some(usage(10, 0)), first);
+        traffic.record(server, peer, Some(usage(25, 0)), second);
+        assert_eq!(traffic.since(peer, Period::Hour, second), usage(15, 0));
+        assert_eq!(traffic.since(peer, Period::Day, first), usage(25, 0));
+        assert_eq!(traffic.since(peer, Period::Month, second), usage(15, 0));
+    }
+}
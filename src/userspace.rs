@@ -0,0 +1,70 @@
+use crate::nat::Hooks;
+use crate::netlink;
+use crate::wghelper::Server;
+use boringtun::device::{DeviceConfig, DeviceHandle};
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, Mutex};
+use wireguard_control::Backend;
+
+/// The embedded WireGuard devices by interface name. Each one runs on threads
+/// of its own and takes its TUN device and UAPI socket with it when dropped.
+static DEVICES: LazyLock<Mutex<HashMap<String, DeviceHandle>>> = LazyLock::new(Default::default);
+
+/// Creates a TUN device run by an embedded WireGuard implementation and
+/// configures it like the kernel backend would, running the server's hooks
+/// around it. If any step fails, the device is removed again.
+pub async fn up(server: &Server, hooks: &Hooks) -> Result<(), String> {
+    netlink::run_hooks(&hooks.pre_up, &server.name).await?;
+    create(&server.name)?;
+
+    let result = match configure(server) {
+        Ok(()) => netlink::setup(server).await,
+        Err(err) => Err(err),
+    };
+    let result = match result {
+        Ok(()) => netlink::run_hooks(&hooks.post_up, &server.name).await,
+        Err(err) => Err(err),
+    };
+    if result.is_err() {
+        remove(&server.name);
+    }
+    result
+}
+
+/// Stops the embedded device of an interface, running its hooks around it.
+pub async fn down(server: &Server, hooks: &Hooks) -> Result<(), String> {
+    netlink::run_hooks(&hooks.pre_down, &server.name).await?;
+    if !remove(&server.name) {
+        return Err(format!("{} is not running", server.name));
+    }
+    netlink::run_hooks(&hooks.post_down, &server.name).await
+}
+
+/// Brings a running device in line with the server, through its UAPI socket.
+pub fn configure(server: &Server) -> Result<(), String> {
+    netlink::configure(server, Backend::Userspace)
+}
+
+/// The names of the interfaces with an embedded device.
+pub fn interfaces() -> HashSet<String> {
+    DEVICES.lock().unwrap().keys().cloned().collect()
+}
+
+pub fn is_running(name: &str) -> bool {
+    DEVICES.lock().unwrap().contains_key(name)
+}
+
+fn create(name: &str) -> Result<(), String> {
+    let mut devices = DEVICES.lock().unwrap();
+    if devices.contains_key(name) {
+        return Err(format!("{} already exists", name));
+    }
+    let device = DeviceHandle::new(name, DeviceConfig::default())
+        .map_err(|err| format!("Failed to create {}: {}", name, err))?;
+    devices.insert(name.to_string(), device);
+    Ok(())
+}
+
+fn remove(name: &str) -> bool {
+    DEVICES.lock().unwrap().remove(name).is_some()
+}
@@ -1,269 +1,497 @@
+use crate::acl::AclRule;
+use crate::backend;
+use crate::error::Error;
+use crate::ipam::{self, Network, Pool};
+use crate::masterkey;
+use crate::metrics;
+use crate::nat::{Hooks, Nat};
+use crate::netlink;
+use crate::nft::Firewall;
+use crate::quota::Quota;
+use crate::schedule::{AccessWindow, Expiry};
+use crate::storage::{self, Change};
+use crate::tc::RateLimit;
+use chrono::{DateTime, Utc};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use serde::{Deserialize, Serialize};
-use std::process::Stdio;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::{collections::HashSet, fmt::Write};
-use tokio::{
-    fs::File,
-    io::{AsyncWriteExt, BufWriter},
-    process::Command,
-};
-
-const IFUP: &str =
-    "iptables -A FORWARD -i %i -j ACCEPT; iptables -t nat -A POSTROUTING -o enp0s3 -j MASQUERADE";
-const IFDOWN: &str =
-    "iptables -D FORWARD -i %i -j ACCEPT; iptables -t nat -D POSTROUTING -o enp0s3 -j MASQUERADE";
-
-const PATH: &str = "./interfaces.toml";
+use tokio::process::Command;
+use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
+    #[serde(default)]
+    pub id: Uuid,
     pub name: String,
-    pub address: String,
+    pub address: Ipv4Net,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub address6: Option<Ipv6Net>,
     pub prikey: String,
     pub pubkey: String,
     pub enabled: bool,
+    /// Set while the peer is disabled because it used up its quota.
+    #[serde(default)]
+    pub over_quota: bool,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub allowed_ips: Vec<IpNet>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub quota: Option<Quota>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub rate_limit: Option<RateLimit>,
+    /// When the peer may connect. A peer without windows may always connect.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub schedule: Vec<AccessWindow>,
+    // Serializes as an array of tables, so it has to stay last.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub acl: Vec<AclRule>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Server {
+    #[serde(default)]
+    pub id: Uuid,
     pub path: String,
     pub name: String,
-    pub address: String,
-    pub subnet: usize,
+    #[serde(deserialize_with = "ipam::deserialize_address")]
+    pub address: Ipv4Net,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub address6: Option<Ipv6Net>,
     pub port: u16,
+    /// Overrides the global endpoint written to this interface's client configs.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub backend: backend::Kind,
+    #[serde(default)]
+    pub firewall: Firewall,
+    /// Blocks forwarding between the peers of this interface.
+    #[serde(default)]
+    pub isolation: bool,
     pub prikey: String,
     pub pubkey: String,
+    // The TOML serializer cannot write plain values after tables, so the
+    // fields that serialize as tables have to stay at the end.
+    #[serde(default)]
+    pub nat: Nat,
+    #[serde(default)]
+    pub hooks: Hooks,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub peers: Vec<Peer>,
+    /// Expired peers that were archived rather than removed.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub archive: Vec<Peer>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Peer {
+    /// The peer's tunnel addresses, one per address family.
+    pub fn addresses(&self) -> Vec<IpNet> {
+        let mut addresses = vec![IpNet::V4(self.address)];
+        addresses.extend(self.address6.map(IpNet::V6));
+        addresses
+    }
+
+    /// The networks routed to the peer: its own addresses as host routes and
+    /// whatever extra networks sit behind it.
+    pub fn routes(&self) -> Vec<IpNet> {
+        let mut routes: Vec<IpNet> = self
+            .addresses()
+            .iter()
+            .map(|address| IpNet::new(address.addr(), address.max_prefix_len()).unwrap())
+            .collect();
+        routes.extend(self.allowed_ips.iter().copied());
+        routes
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Whether the peer comes and goes with time.
+    pub fn is_scheduled(&self) -> bool {
+        self.expires_at.is_some() || !self.schedule.is_empty()
+    }
+
+    /// Whether the peer belongs on the running interface: it is enabled, has
+    /// not expired and is inside one of its access windows, if it has any.
+    pub fn active(&self, now: DateTime<Utc>) -> bool {
+        self.enabled
+            && !self.is_expired(now)
+            && (self.schedule.is_empty() || self.schedule.iter().any(|window| window.contains(now)))
+    }
+}
+
+impl Server {
+    /// The server's tunnel addresses, one per address family.
+    pub fn addresses(&self) -> Vec<IpNet> {
+        let mut addresses = vec![IpNet::V4(self.address)];
+        addresses.extend(self.address6.map(IpNet::V6));
+        addresses
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Wg {
+    /// The public host name or address clients connect to, or `auto` to use
+    /// one of the host's own addresses found at startup.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub endpoint: Option<String>,
+    #[serde(skip)]
+    pub detected_endpoint: Option<String>,
+    #[serde(default)]
+    pub expired_peers: Expiry,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub servers: Vec<Server>,
 }
 
 impl Wg {
-    pub async fn create(&mut self, name: &str, cidr: &str, port: u16) {
-        let (prikey, pubkey) = Self::get_keys().await;
-
-        let subnet = cidr.chars().filter(|ch| *ch == 'x').count();
-        let subnet = 32 - 8 * subnet;
+    /// Adds a server with a fresh key pair and default settings, returning it
+    /// so the caller can fill in the optional ones.
+    pub fn create(&mut self, name: &str, address: Ipv4Net, port: u16) -> &mut Server {
+        let (prikey, pubkey) = Self::get_keys();
 
         let server = Server {
+            id: Uuid::new_v4(),
             path: format!("/tmp/{}.conf", name),
             name: name.into(),
-            address: cidr.into(),
-            subnet,
+            address,
+            address6: None,
             port,
+            endpoint: None,
+            backend: backend::Kind::Kernel,
+            firewall: Firewall::Nftables,
+            isolation: false,
             prikey,
             pubkey,
+            nat: Nat::default(),
+            hooks: Hooks::default(),
             peers: vec![],
+            archive: vec![],
         };
 
         self.servers.push(server);
+        self.servers.last_mut().unwrap()
     }
 
     pub async fn start(&self, server_id: usize) -> Result<(), String> {
-        self.wg_config(server_id).await;
-        let config_file = &self.servers[server_id].path;
-        let output = Command::new("wg-quick")
-            .args(["up", config_file])
-            .output()
-            .await
-            .unwrap();
-
-        if output.status.success() {
-            return Ok(());
-        }
-
-        Err(String::from_utf8(output.stderr).unwrap())
+        backend::get().up(&self.servers[server_id]).await
     }
 
     pub async fn stop(&self, server_id: usize) -> Result<(), String> {
-        self.wg_config(server_id).await;
-        let config_file = &self.servers[server_id].path;
-        let output = Command::new("wg-quick")
-            .args(["down", config_file])
-            .output()
-            .await
-            .unwrap();
+        backend::get().down(&self.servers[server_id]).await
+    }
 
-        if output.status.success() {
-            return Ok(());
+    pub fn read_state() -> Result<Wg, String> {
+        let mut state = storage::get().load()?;
+        // The keys were in plaintext before, in the backups too.
+        if state.seal_keys()? {
+            storage::get().save(&state)?;
+            storage::get().discard_history()?;
         }
-
-        Err(String::from_utf8(output.stderr).unwrap())
+        Ok(state)
     }
 
-    pub fn read_state() -> Wg {
-        let config = std::fs::read_to_string(PATH).unwrap();
-        let state: Wg = toml::from_str(&config).unwrap();
-        state
+    /// Checks that every private key can be decrypted with the master key,
+    /// and encrypts the ones still in plaintext if there is one. Returns
+    /// whether any changed.
+    fn seal_keys(&mut self) -> Result<bool, String> {
+        let mut changed = false;
+        for server in &mut self.servers {
+            changed |= masterkey::reseal(&mut server.prikey)
+                .map_err(|err| format!("{}: {}", server.name, err))?;
+            for peer in server.peers.iter_mut().chain(&mut server.archive) {
+                changed |= masterkey::reseal(&mut peer.prikey)
+                    .map_err(|err| format!("{} peer {}: {}", server.name, peer.name, err))?;
+            }
+        }
+        Ok(changed)
     }
 
-    pub async fn dump_state(state: &Wg) {
-        let config = toml::to_string(&state).unwrap();
-        tokio::fs::write(PATH, config.as_bytes()).await.unwrap();
+    /// Encrypts every private key in the stored state with the new master
+    /// key, for `rest-wg rekey`.
+    pub fn rekey() -> Result<(), String> {
+        let new_key = masterkey::new_key()?;
+        let mut state = storage::get().load()?;
+        let mut count = 0;
+        for server in &mut state.servers {
+            let keys = std::iter::once(&mut server.prikey).chain(
+                server
+                    .peers
+                    .iter_mut()
+                    .chain(&mut server.archive)
+                    .map(|peer| &mut peer.prikey),
+            );
+            for prikey in keys {
+                let plain =
+                    masterkey::open(prikey).map_err(|err| format!("{}: {}", server.name, err))?;
+                *prikey = masterkey::seal_with(&new_key, &plain);
+                count += 1;
+            }
+        }
+        storage::get().save(&state)?;
+        // What the old master key could still decrypt goes as well.
+        storage::get().discard_history()?;
+        println!("Encrypted {} private keys with the new master key", count);
+        Ok(())
     }
 
-    pub async fn get_keys() -> (String, String) {
-        let output = Command::new("wg")
-            .arg("genkey")
-            .output()
-            .await
-            .expect("Failed to execute wg genkey");
-
-        let private_key = String::from_utf8(output.stdout).unwrap().trim().to_string();
-
-        let mut command = Command::new("wg")
-            .arg("pubkey")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("Failed to execute wg pubkey");
-
-        command
-            .stdin
-            .as_mut()
-            .expect("Failed to get stdin for wg pubkey")
-            .write_all(private_key.as_bytes())
-            .await
-            .expect("Failed to write private key to wg pubkey");
+    /// Gives every server and peer loaded from an older state file a fresh id.
+    pub fn assign_ids(&mut self) {
+        for server in &mut self.servers {
+            if server.id.is_nil() {
+                server.id = Uuid::new_v4();
+            }
+            for peer in &mut server.peers {
+                if peer.id.is_nil() {
+                    peer.id = Uuid::new_v4();
+                }
+            }
+        }
+    }
 
-        let output = command
-            .wait_with_output()
-            .await
-            .expect("Failed to get output from wg pubkey");
+    /// Finds a server by its id or by its name.
+    pub fn server_index(&self, key: &str) -> Option<usize> {
+        let id = Uuid::parse_str(key).ok();
+        self.servers
+            .iter()
+            .position(|server| Some(server.id) == id || server.name == key)
+    }
 
-        let public_key = String::from_utf8(output.stdout).unwrap().trim().to_string();
+    /// Finds a peer of the given server by its id or by its name.
+    pub fn peer_index(&self, server_id: usize, key: &str) -> Option<usize> {
+        let id = Uuid::parse_str(key).ok();
+        self.servers.get(server_id).and_then(|server| {
+            server
+                .peers
+                .iter()
+                .position(|peer| Some(peer.id) == id || peer.name == key)
+        })
+    }
 
-        (private_key, public_key)
+    /// Like `server_index`, but fails with the error a handler responds with.
+    pub fn server_id(&self, key: &str) -> Result<usize, Error> {
+        self.server_index(key)
+            .ok_or_else(|| Error::InterfaceNotFound(key.into()))
     }
 
-    pub async fn create_peer(&mut self, name: &str, server_id: usize) {
-        let (prikey, pubkey) = Self::get_keys().await;
-        if let Some(server) = self.servers.get_mut(server_id) {
-            let peer = Peer {
-                name: name.into(),
-                address: format!(
-                    "{}/{}",
-                    server
-                        .address
-                        .replace('x', &(server.peers.len() + 2).to_string()),
-                    server.subnet
-                ),
-                prikey,
-                pubkey,
-                enabled: true,
-            };
-            server.peers.push(peer);
-        }
+    /// Like `peer_index`, but fails with the error a handler responds with.
+    pub fn peer_id(&self, server_id: usize, key: &str) -> Result<usize, Error> {
+        self.peer_index(server_id, key)
+            .ok_or_else(|| Error::PeerNotFound(key.into()))
     }
 
-    pub async fn hot_reload(&self, server_id: usize) {
-        if let Some(server) = self.servers.get(server_id) {
-            self.wg_config(server_id).await;
-            let config_file = &server.path;
+    /// Saves the parts of the state that changed.
+    pub async fn dump_state(state: &Wg, changes: &[Change]) -> Result<(), Error> {
+        let _timer = metrics::STATE_SAVE_DURATION.start_timer();
+        let failed = |err: &dyn std::fmt::Display| {
+            Error::Internal(format!("Failed to save the state: {}", err))
+        };
+        tokio::task::block_in_place(|| storage::get().save_changes(state, changes))
+            .map_err(|err| failed(&err))
+    }
 
-            let output = Command::new("wg-quick")
-                .args(["strip", config_file])
-                .output()
-                .await
-                .unwrap();
+    /// Generates a key pair, with the private key encrypted for storing if
+    /// there is a master key.
+    pub fn get_keys() -> (String, String) {
+        let (prikey, pubkey) = backend::get().generate_keys();
+        (masterkey::seal(&prikey), pubkey)
+    }
 
-            let output = String::from_utf8(output.stdout).unwrap();
+    /// Derives the public key for a private key, failing if it is not one.
+    pub fn pubkey(private_key: &str) -> Result<String, String> {
+        netlink::pubkey(private_key)
+    }
 
-            let update_file_name = format!("/tmp/update_{}.conf", server.name);
-            let mut update_file = File::create(&update_file_name).await.unwrap();
-            update_file.write_all(output.as_bytes()).await.unwrap();
+    /// Checks a name against the rules the kernel applies to interface names.
+    pub fn valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() < 16
+            && name
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || "_=+.-".contains(ch))
+    }
 
-            let output = Command::new("wg")
-                .args(["syncconf", &server.name, &update_file_name])
-                .output()
-                .await
-                .unwrap();
+    /// Adds a peer with the given static addresses, or with the lowest free
+    /// address of each of the server's networks where none is given, returning
+    /// it so the caller can fill in the optional settings.
+    pub fn create_peer(
+        &mut self,
+        name: &str,
+        server_id: usize,
+        address: Option<Ipv4Addr>,
+        address6: Option<Ipv6Addr>,
+    ) -> Result<&mut Peer, String> {
+        let server = self.servers.get_mut(server_id).ok_or("no such interface")?;
+        let address = Self::take_address(Some(Pool::v4(server, None)), address)?
+            .ok_or_else(|| format!("{} has no free addresses", server.name))?;
+        let address6 = Self::take_address(Pool::v6(server, None), address6)?;
+
+        let (prikey, pubkey) = Self::get_keys();
+        let peer = Peer {
+            id: Uuid::new_v4(),
+            name: name.into(),
+            address,
+            address6,
+            prikey,
+            pubkey,
+            enabled: true,
+            over_quota: false,
+            expires_at: None,
+            allowed_ips: vec![],
+            quota: None,
+            rate_limit: None,
+            schedule: vec![],
+            acl: vec![],
+        };
+        server.peers.push(peer);
+        Ok(server.peers.last_mut().unwrap())
+    }
 
-            dbg!(output);
+    /// Reserves the requested address, or allocates one if none was requested.
+    /// Without a pool, meaning the server lacks that address family, nothing is
+    /// handed out.
+    pub fn take_address<N: Network>(
+        pool: Option<Pool<N>>,
+        address: Option<N::Addr>,
+    ) -> Result<Option<N>, String> {
+        let mut pool = match pool {
+            Some(pool) => pool,
+            None if address.is_some() => {
+                return Err("the interface has no network for this address".into())
+            }
+            None => return Ok(None),
+        };
+        match address {
+            Some(address) => pool
+                .reserve(address)
+                .map(Some)
+                .ok_or_else(|| format!("{} is not a free address", address)),
+            None => Ok(pool.allocate()),
         }
     }
 
-    async fn wg_config(&self, server_id: usize) {
+    /// Puts the server's settings into effect on its running interface.
+    pub async fn hot_reload(&self, server_id: usize) -> Result<(), Error> {
         if let Some(server) = self.servers.get(server_id) {
-            let mut file = BufWriter::new(File::create(&server.path).await.unwrap());
-
-            file.write_all(b"[Interface]\n").await.unwrap();
-            file.write_all(
-                format!(
-                    "Address = {}/{}\n",
-                    server.address.replace('x', "1"),
-                    server.subnet
-                )
-                .as_bytes(),
-            )
-            .await
-            .unwrap();
-            file.write_all(format!("ListenPort = {}\n", server.port).as_bytes())
-                .await
-                .unwrap();
-            file.write_all(format!("PrivateKey = {}\n", server.prikey).as_bytes())
-                .await
-                .unwrap();
-            file.write_all(format!("PostUp = {}\n", IFUP).as_bytes())
+            backend::get()
+                .sync(server)
                 .await
-                .unwrap();
-            file.write_all(format!("PostDown = {}\n\n", IFDOWN).as_bytes())
-                .await
-                .unwrap();
-
-            for peer in &server.peers {
-                file.write_all(b"[Peer]\n").await.unwrap();
-                file.write_all(format!("PublicKey = {}\n", peer.pubkey).as_bytes())
-                    .await
-                    .unwrap();
-                let address: String = peer.address.split('/').take(1).collect();
-                file.write_all(format!("AllowedIPs = {}/32\n\n", address).as_bytes())
-                    .await
-                    .unwrap();
-            }
-
-            file.flush().await.unwrap();
+                .map_err(Error::backend("reload", &server.name))?;
         }
+        Ok(())
     }
 
-    pub fn peer_config(&self, server_id: usize, peer_id: usize) -> String {
+    pub fn peer_config(&self, server_id: usize, peer_id: usize) -> Result<String, String> {
         let mut output = String::new();
         if let Some(server) = self.servers.get(server_id) {
             if let Some(peer) = server.peers.get(peer_id) {
+                let prikey = masterkey::open(&peer.prikey)?;
                 writeln!(&mut output, "[Interface]").unwrap();
-                writeln!(&mut output, "Address = {}", peer.address).unwrap();
-                writeln!(&mut output, "PrivateKey  = {}\n", peer.prikey).unwrap();
+                writeln!(&mut output, "Address = {}", join(&peer.addresses())).unwrap();
+                writeln!(&mut output, "PrivateKey  = {}\n", prikey).unwrap();
                 writeln!(&mut output, "[Peer]").unwrap();
                 writeln!(&mut output, "PublicKey = {}", server.pubkey).unwrap();
-                writeln!(
-                    &mut output,
-                    "AllowedIPs = {}/{}",
-                    server.address.replace('x', "0"),
-                    server.subnet
-                )
-                .unwrap();
-                writeln!(&mut output, "Endpoint = 140.238.242.140:{}", server.port).unwrap();
+                let networks: Vec<IpNet> = server.addresses().iter().map(IpNet::trunc).collect();
+                writeln!(&mut output, "AllowedIPs = {}", join(&networks)).unwrap();
+                if let Some(endpoint) = self.endpoint(server) {
+                    writeln!(&mut output, "Endpoint = {}:{}", endpoint, server.port).unwrap();
+                }
             }
         }
-        output
+        Ok(output)
     }
 
-    pub async fn server_status() -> HashSet<String> {
-        let mut status = HashSet::new();
-        let output = Command::new("wg")
-            .args(["show", "interfaces"])
-            .output()
-            .await
-            .unwrap();
+    /// The endpoint clients of the server connect to, ready to be followed by
+    /// a port: the server's own setting, else the global one, else the
+    /// detected one.
+    pub fn endpoint(&self, server: &Server) -> Option<String> {
+        let endpoint = server
+            .endpoint
+            .as_ref()
+            .or_else(|| {
+                self.endpoint
+                    .as_ref()
+                    .filter(|endpoint| *endpoint != "auto")
+            })
+            .or(self.detected_endpoint.as_ref())?;
+        match endpoint.parse::<Ipv6Addr>() {
+            Ok(address) => Some(format!("[{}]", address)),
+            Err(_) => Some(endpoint.clone()),
+        }
+    }
 
-        let output: String = String::from_utf8(output.stdout).unwrap();
-        for server in output.trim().split(' ') {
-            status.insert(server.into());
+    /// Checks an endpoint, which is either an IP address or a host name.
+    pub fn valid_endpoint(endpoint: &str) -> bool {
+        let endpoint = endpoint.trim_start_matches('[').trim_end_matches(']');
+        if endpoint.parse::<IpAddr>().is_ok() {
+            return true;
         }
+        !endpoint.is_empty()
+            && endpoint.len() <= 253
+            && endpoint.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label
+                        .chars()
+                        .all(|ch| ch.is_ascii_alphanumeric() || ch == '-')
+            })
+    }
+
+    /// Picks an endpoint from the host's global addresses, preferring public
+    /// IPv4 over public IPv6 over anything else.
+    pub async fn detect_endpoint() -> Option<String> {
+        let output = Command::new("ip")
+            .args(["-o", "addr", "show", "scope", "global"])
+            .output()
+            .await
+            .ok()?;
+        let output = String::from_utf8(output.stdout).ok()?;
+
+        let addresses: Vec<IpAddr> = output
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line
+                    .split_whitespace()
+                    .skip_while(|field| *field != "inet" && *field != "inet6");
+                fields.nth(1)?.split('/').next()?.parse().ok()
+            })
+            .collect();
+
+        let public = |address: &&IpAddr| match address {
+            IpAddr::V4(address) => {
+                !address.is_private() && !address.is_loopback() && !address.is_link_local()
+            }
+            IpAddr::V6(address) => (address.segments()[0] & 0xe000) == 0x2000,
+        };
+        addresses
+            .iter()
+            .filter(public)
+            .min_by_key(|address| address.is_ipv6())
+            .or_else(|| addresses.first())
+            .map(IpAddr::to_string)
+    }
 
-        status
+    /// The names of the interfaces that are running.
+    pub async fn server_status() -> Result<HashSet<String>, Error> {
+        backend::get()
+            .interfaces()
+            .await
+            .map_err(|stderr| Error::Backend {
+                message: "Failed to list interfaces".into(),
+                stderr,
+            })
     }
 }
+
+/// Joins networks into the comma separated form used by WireGuard configs.
+pub fn join(networks: &[IpNet]) -> String {
+    networks
+        .iter()
+        .map(IpNet::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}